@@ -1,15 +1,135 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+use chrono::{DateTime, Days, LocalResult, Months, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::{caller, is_controller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{
+    BoundedStorable, Cell, DefaultMemoryImpl, Log, StableBTreeMap, Storable,
+};
+use pbkdf2::pbkdf2_hmac;
+use sha1::Sha1;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::time::Duration;
 use std::{borrow::Cow, cell::RefCell};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+// Principals are at most 29 bytes long
+type PrincipalKey = Blob<29>;
+// Sessions are keyed by the SHA-256 digest of their token
+type SessionKey = Blob<32>;
+
+// Password hashes are stored as "<scheme>$<iterations>$<salt hex>$<hash hex>".
+// Hashes without a scheme prefix are legacy unsalted SHA-256 hex digests.
+const PASSWORD_HASH_SCHEME: &str = "pbkdf2-sha256";
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+const PASSWORD_SALT_LEN: usize = 16;
+
+const MAX_USERNAME_LEN: usize = 64;
+const MAX_EMAIL_LEN: usize = 254;
+// Long passwords only make hashing more expensive
+const MAX_PASSWORD_LEN: usize = 128;
+
+// Accounts are locked after this many failed logins in a row
+const MAX_FAILED_LOGINS: u32 = 5;
+// The lockout doubles with every further failure, up to a day
+const LOGIN_LOCKOUT_BASE_NANOS: u64 = 60 * 1_000_000_000;
+const LOGIN_LOCKOUT_MAX_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Email verification codes are 6 digits, valid for a day and for a few guesses
+const EMAIL_CODE_DIGITS: u32 = 6;
+const EMAIL_CODE_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_EMAIL_CODE_ATTEMPTS: u32 = 5;
+// New codes can be sent after a minute, doubling with every code sent, up to a day
+const EMAIL_RESEND_BASE_NANOS: u64 = 60 * 1_000_000_000;
+const EMAIL_RESEND_MAX_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// RFC 6238 TOTP parameters, the defaults understood by authenticator apps
+const TOTP_ISSUER: &str = "EventSphere";
+const TOTP_SECRET_LEN: usize = 20;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_PERIOD_SECS: u64 = 30;
+// Codes from the previous and next period are accepted to allow for clock drift
+const TOTP_ALLOWED_DRIFT_STEPS: u64 = 1;
+const TOTP_RECOVERY_CODES: usize = 8;
+
+// Password reset tokens are single-use and valid for an hour
+const RESET_TOKEN_LEN: usize = 32;
+const RESET_TOKEN_TTL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// An account gets a new reset email at most every 5 minutes
+const RESET_REQUEST_COOLDOWN_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+// API keys are presented as `esk_<id>_<secret>` in place of a session token
+const API_KEY_PREFIX: &str = "esk_";
+const API_KEY_SECRET_LEN: usize = 32;
+const MAX_API_KEY_NAME_LEN: usize = 64;
+const MAX_API_KEY_EVENTS: usize = 32;
+
+// Largest page of audit entries returned at once
+const MAX_AUDIT_PAGE_SIZE: u32 = 100;
+// A filtered page stops after this many entries even if it isn't full
+const MAX_AUDIT_SCAN: u64 = 5_000;
+// A page of events by start date stops after this many events even if it isn't full
+const MAX_EVENT_SCAN: usize = 5_000;
+// Largest page of events, tickets or attendees returned at once
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Event dates without a timezone are interpreted in UTC
+const DEFAULT_TIMEZONE: &str = "UTC";
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Recurring series expand to at most this many occurrences
+const MAX_SERIES_OCCURRENCES: usize = 100;
+// Event text is bounded so events fit in storage
+const MAX_EVENT_NAME_LEN: usize = 200;
+const MAX_EVENT_LOCATION_LEN: usize = 300;
+const MAX_EVENT_DETAILS_LEN: usize = 2000;
+const MAX_CO_ORGANIZERS: usize = 20;
+const MAX_ATTENDEE_NAME_LEN: usize = 200;
+// Events have at most 5 tags of up to 32 letters, digits or dashes
+const MAX_EVENT_TAGS: usize = 5;
+const MAX_TAG_LEN: usize = 32;
+const MAX_CATEGORY_NAME_LEN: usize = 64;
+const MAX_CATEGORY_DESCRIPTION_LEN: usize = 256;
+// Venue names and address lines
+const MAX_VENUE_FIELD_LEN: usize = 100;
+const MAX_ACCESSIBILITY_NOTES_LEN: usize = 256;
+// Venues are indexed by 8 character geohashes, cells of about 38 by 19 meters
+const GEOHASH_PRECISION: usize = 8;
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+// Shortest length of a degree of latitude, so cells are never assumed larger than they are
+const KM_PER_DEGREE: f64 = 110.574;
+const MAX_NEARBY_RADIUS_KM: f64 = 500.0;
+// Only the closest events are returned
+const MAX_NEARBY_EVENTS: usize = 100;
+// Search terms are words of at least 2 characters, truncated to 32
+const MIN_SEARCH_TERM_LEN: usize = 2;
+const MAX_SEARCH_TERM_LEN: usize = 32;
+const MAX_SEARCH_QUERY_TERMS: usize = 8;
+// Shorter query words only match whole words, not the start of longer ones
+const MIN_SEARCH_PREFIX_LEN: usize = 3;
+// Index entries read per query word, exact matches are read first
+const MAX_SEARCH_TERM_MATCHES: usize = 1_000;
+const SEARCH_PAGE_SIZE: usize = 20;
+// Matches in the name rank above the location, which ranks above the details
+const SEARCH_WEIGHT_NAME: u32 = 3;
+const SEARCH_WEIGHT_LOCATION: u32 = 2;
+const SEARCH_WEIGHT_DETAILS: u32 = 1;
+
+// How often published events past their end date are marked completed
+const EVENT_COMPLETION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const SESSION_TOKEN_LEN: usize = 32;
+// Sessions expire 24 hours after login
+const SESSION_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Event {
@@ -17,12 +137,142 @@ struct Event {
     event_name: String,
     details: String,
     location: String,
-    start_date: Date,
-    end_date: Date,
+    // Start and exclusive end in nanoseconds since the Unix epoch
+    start_date: u64,
+    end_date: u64,
+    // IANA name of the timezone the event takes place in, e.g. "Africa/Nairobi"
+    timezone: String,
+    timestamp: u64,
+    organizer_id: u64,
+    // Users invited by the organizer to help manage the event
+    co_organizer_ids: Vec<u64>,
+    status: EventStatus,
+    // Recurring series the event is an occurrence of
+    series_id: Option<u64>,
+    category_id: Option<u64>,
+    // Lowercase free-form tags, None for events created before tags existed
+    tags: Option<Vec<String>>,
+    // Venue the event takes place at, its capacity limits the tickets
+    venue_id: Option<u64>,
+}
+
+// Events start as drafts, only published events are listed and sell tickets
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq, Eq,
+)]
+enum EventStatus {
+    #[default]
+    Draft,
+    Published,
+    Cancelled,
+    // Put on hold until new dates are set and it is published again
+    Postponed,
+    // Set automatically once a published event has ended
+    Completed,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+// When a series repeats, modelled on RFC 5545 recurrence rules
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecurrenceRule {
+    frequency: Frequency,
+    // Repeat every `interval` days, weeks or months
+    interval: u32,
+    // The series ends after `count` occurrences or with the last one starting before `until`
+    count: Option<u32>,
+    until: Option<u64>,
+    // Local dates (YYYY-MM-DD) that are skipped
+    exceptions: Vec<String>,
+}
+
+// Recurring event, each occurrence is an `Event` with its own tickets and attendees
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventSeries {
+    id: u64,
+    organizer_id: u64,
+    rule: RecurrenceRule,
+    occurrence_ids: Vec<u64>,
+    created_at: u64,
+}
+
+// Event as stored before dates became instants, and before events had organizers or a status
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyEvent {
+    id: u64,
+    event_name: String,
+    details: String,
+    location: String,
+    start_date: LegacyDate,
+    end_date: LegacyDate,
     timestamp: u64,
+    organizer_id: Option<u64>,
+    co_organizer_ids: Option<Vec<u64>>,
+    status: Option<EventStatus>,
+}
+
+// Encoded event as stored in the first events map, which had a 1024 byte bound and kept the
+// attendees inside the event
+struct LegacyEventRecord(Vec<u8>);
+
+#[derive(candid::CandidType, Deserialize)]
+struct InlineAttendees {
     attendees: Vec<Attendee>,
 }
 
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyDate {
+    year: u32,
+    month: u32,
+    day: u32,
+}
+
+impl LegacyDate {
+    // Midnight UTC of the date, None for dates that don't exist such as 99-99-0
+    fn to_instant(&self) -> Option<u64> {
+        let date = NaiveDate::from_ymd_opt(self.year as i32, self.month, self.day)?;
+        let nanos = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_nanos_opt()?;
+        u64::try_from(nanos).ok()
+    }
+}
+
+impl From<LegacyEvent> for Event {
+    fn from(event: LegacyEvent) -> Self {
+        // Invalid legacy dates fall back to the creation time
+        let start_date = event.start_date.to_instant().unwrap_or(event.timestamp);
+        let end_date = event
+            .end_date
+            .to_instant()
+            .map(|end_date| end_date + DAY_NANOS)
+            .unwrap_or(start_date)
+            .max(start_date);
+        Event {
+            id: event.id,
+            event_name: event.event_name,
+            details: event.details,
+            location: event.location,
+            start_date,
+            end_date,
+            timezone: DEFAULT_TIMEZONE.to_string(),
+            timestamp: event.timestamp,
+            // Events created before organizers existed are only managed by admins
+            organizer_id: event.organizer_id.unwrap_or(u64::MAX),
+            co_organizer_ids: event.co_organizer_ids.unwrap_or_default(),
+            // Events were public as soon as they were created
+            status: event.status.unwrap_or(EventStatus::Published),
+            series_id: None,
+            category_id: None,
+            tags: None,
+            venue_id: None,
+        }
+    }
+}
+
 #[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, Clone)]
 struct User {
     id: u64,
@@ -32,14 +282,43 @@ struct User {
     role: UserRole,
     created_at: u64,
     updated_at: Option<u64>,
+    // Identity bound to the account, None for legacy accounts that haven't linked one yet
+    principal: Option<Principal>,
+    // Set once the user proved ownership of `email`
+    email_verified_at: Option<u64>,
 }
 
-// Define the Date struct
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Date {
-    year: u32,
-    month: u32,
-    day: u32,
+// Public view of a user returned by the endpoints, never includes the password hash.
+// Email and principal are only visible to the account owner and admins.
+#[derive(candid::CandidType, Serialize, Deserialize, Debug, Clone)]
+struct UserProfile {
+    id: u64,
+    username: String,
+    email: Option<String>,
+    email_verified: bool,
+    role: UserRole,
+    created_at: u64,
+    updated_at: Option<u64>,
+    principal: Option<Principal>,
+}
+
+impl User {
+    fn to_profile(&self, include_private: bool) -> UserProfile {
+        UserProfile {
+            id: self.id,
+            username: self.username.clone(),
+            email: include_private.then(|| self.email.clone()),
+            email_verified: self.email_verified_at.is_some(),
+            role: self.role.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            principal: if include_private {
+                self.principal
+            } else {
+                None
+            },
+        }
+    }
 }
 
 #[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
@@ -54,8 +333,9 @@ enum TicketType {
 #[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
 
 enum UserRole {
-    #[default]
     Admin,
+    Organizer,
+    #[default]
     User,
 }
 
@@ -70,28 +350,198 @@ impl fmt::Display for TicketType {
     }
 }
 
-impl Date {
-    // Constructor method to create a new Date instance
-    fn new(day: u32, month: u32, year: u32) -> Self {
-        Date { year, month, day }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Session {
+    user_id: u64,
+    created_at: u64,
+    expires_at: u64,
+}
+
+// Outstanding password reset, stored under the hash of the emailed token
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PasswordReset {
+    user_id: u64,
+    expires_at: u64,
+}
+
+// Strength requirements for new passwords, configurable by admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PasswordPolicy {
+    min_length: u32,
+    require_uppercase: bool,
+    require_lowercase: bool,
+    require_digit: bool,
+    require_symbol: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            require_uppercase: false,
+            require_lowercase: false,
+            require_digit: true,
+            require_symbol: false,
+        }
     }
+}
 
-    // Method to parse a date string in "DD-MM-YYYY" format and create a Date instance
-    fn from_string(date_string: &str) -> Option<Self> {
-        let parts: Vec<&str> = date_string.split('-').collect();
-        if parts.len() == 3 {
-            if let (Ok(day), Ok(month), Ok(year)) = (
-                parts[0].parse::<u32>(),
-                parts[1].parse::<u32>(),
-                parts[2].parse::<u32>(),
-            ) {
-                return Some(Date::new(day, month, year));
-            }
+// What an API key may be used for
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum ApiScope {
+    ReadTickets,
+    ReadAttendees,
+    PurchaseTickets,
+}
+
+// Machine credential issued by an organizer, only the hash of its secret is stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiKey {
+    id: u64,
+    name: String,
+    issuer_id: u64,
+    key_hash: String,
+    scopes: Vec<ApiScope>,
+    // Events the key is restricted to, empty for every event the issuer organizes
+    event_ids: Vec<u64>,
+    expires_at: Option<u64>,
+    created_at: u64,
+    last_used_at: Option<u64>,
+}
+
+// API key as returned by the endpoints, without the hash of its secret
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ApiKeyInfo {
+    id: u64,
+    name: String,
+    issuer_id: u64,
+    scopes: Vec<ApiScope>,
+    event_ids: Vec<u64>,
+    expires_at: Option<u64>,
+    created_at: u64,
+    last_used_at: Option<u64>,
+}
+
+impl ApiKey {
+    fn to_info(&self) -> ApiKeyInfo {
+        ApiKeyInfo {
+            id: self.id,
+            name: self.name.clone(),
+            issuer_id: self.issuer_id,
+            scopes: self.scopes.clone(),
+            event_ids: self.event_ids.clone(),
+            expires_at: self.expires_at,
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
         }
-        None
     }
 }
 
+// Identity behind an authenticated call, a user or a partner's API key
+enum Caller {
+    User(User),
+    ApiKey(ApiKey),
+}
+
+// Kind of record an audit entry is about
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum AuditEntity {
+    User,
+    Event,
+    Ticket,
+    ApiKey,
+    Outbox,
+    Settings,
+    Category,
+    Venue,
+}
+
+// Append-only record of a state change made by an update call
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    id: u64,
+    timestamp: u64,
+    caller: Principal,
+    // Account that made the change, None for anonymous calls, controllers and API keys
+    user_id: Option<u64>,
+    api_key_id: Option<u64>,
+    method: String,
+    entity: AuditEntity,
+    target_ids: Vec<u64>,
+    // Human readable description, with `field: old -> new` pairs for updates
+    summary: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AuditFilter {
+    entity: Option<AuditEntity>,
+    target_id: Option<u64>,
+    user_id: Option<u64>,
+}
+
+// Entries are returned newest first, `next_cursor` continues with older entries
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AuditPage {
+    entries: Vec<AuditEntry>,
+    next_cursor: Option<u64>,
+}
+
+// Pending proof of email ownership, the code itself is only stored hashed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EmailVerification {
+    email: String,
+    code_hash: String,
+    expires_at: u64,
+    attempts: u32,
+    // When the code was sent and how many codes were sent before it, None for older codes
+    sent_at: Option<u64>,
+    resends: Option<u32>,
+}
+
+// Email queued for delivery by an off-chain relay
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OutboxMessage {
+    id: u64,
+    recipient: String,
+    subject: String,
+    body: String,
+    created_at: u64,
+}
+
+// Two-factor authentication settings of a user
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TotpConfig {
+    secret: Vec<u8>,
+    // Enrollment only takes effect once a code from the authenticator was confirmed
+    enabled: bool,
+    recovery_code_hashes: Vec<String>,
+    // Time step of the last accepted code, so a code can't be replayed
+    last_used_step: u64,
+    created_at: u64,
+}
+
+// Failed logins of an existing account, keyed by its id so renaming the account keeps them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LoginAttempts {
+    failures: u32,
+    last_failure_at: u64,
+    locked_until: Option<u64>,
+}
+
+// Record of a role assignment, kept for every grant and revocation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RoleChange {
+    id: u64,
+    user_id: u64,
+    previous_role: UserRole,
+    new_role: UserRole,
+    // Principal that made the change, the canister itself for init bootstrapping
+    changed_by: Principal,
+    timestamp: u64,
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Attendee {
     attendee_name: String,
@@ -104,30 +554,197 @@ struct Ticket {
     ticket_type: String,
     ticket_price: u64,
     num_tickets: u32,
+    status: TicketStatus,
+    // User who bought the ticket
+    holder_id: Option<u64>,
+    checked_in_at: Option<u64>,
+}
+
+// The view of a ticket door staff get, it leaves out the price and the holder
+#[derive(candid::CandidType, Serialize, Deserialize, Debug, Clone)]
+struct DoorTicket {
+    ticket_id: u64,
+    event_id: u64,
+    ticket_type: String,
+    status: TicketStatus,
+    checked_in_at: Option<u64>,
+}
+
+impl Ticket {
+    fn to_door_ticket(&self) -> DoorTicket {
+        DoorTicket {
+            ticket_id: self.ticket_id,
+            event_id: self.event_id,
+            ticket_type: self.ticket_type.clone(),
+            status: self.status.clone(),
+            checked_in_at: self.checked_in_at,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+enum TicketStatus {
+    #[default]
+    Available,
+    Sold,
+    CheckedIn,
+    // Unsold ticket of a cancelled event
+    Voided,
+    // Sold ticket of a cancelled event, owed a refund
+    Refundable,
+}
+
+// Ticket as stored before tickets had a status, when sold tickets were removed from storage
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyTicket {
+    ticket_id: u64,
+    event_id: u64,
+    ticket_type: String,
+    ticket_price: u64,
+    num_tickets: u32,
+}
+
+impl From<LegacyTicket> for Ticket {
+    fn from(ticket: LegacyTicket) -> Self {
+        Ticket {
+            ticket_id: ticket.ticket_id,
+            event_id: ticket.event_id,
+            ticket_type: ticket.ticket_type,
+            ticket_price: ticket.ticket_price,
+            num_tickets: ticket.num_tickets,
+            // Purchases used to count tickets down, only a ticket with none left was sold
+            status: if ticket.num_tickets > 0 {
+                TicketStatus::Available
+            } else {
+                TicketStatus::Sold
+            },
+            holder_id: None,
+            checked_in_at: None,
+        }
+    }
+}
+
+// Roles that can be granted to a user for a single event
+#[derive(candid::CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+enum EventRole {
+    // Door staff, may only look up and check in tickets
+    Scanner,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventStaff {
+    event_id: u64,
+    user_id: u64,
+    role: EventRole,
+    granted_by: u64,
+    granted_at: u64,
+}
+
+// Permissions declared by the endpoints, scoped to the account or event they act on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Permission {
+    // Modify or delete an account
+    ManageAccount(u64),
+    // Grant and revoke user roles
+    ManageRoles,
+    // Administer other accounts, e.g. unlock them after failed logins
+    ManageUsers,
+    // Read and acknowledge the queued outgoing emails
+    ManageOutbox,
+    // Read the audit log of all state changes
+    ViewAuditLog,
+    // Create, rename and delete event categories
+    ManageCategories,
+    CreateEvent,
+    // Edit or delete an event and manage its attendees
+    ManageEvent(u64),
+    // Invite and remove co-organizers
+    ManageOrganizers(u64),
+    // Grant and revoke per-event roles such as scanners
+    ManageStaff(u64),
+    // Generate and delete tickets
+    ManageTickets(u64),
+    // Look up and check in tickets at the door
+    CheckInTickets(u64),
+    // List the tickets of an event, including their prices and holders
+    ViewTickets(u64),
+    // List the attendees of an event
+    ViewAttendees(u64),
+    PurchaseTickets(u64),
+    // Edit or delete a venue
+    ManageVenue(u64),
 }
 
 impl Storable for Event {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyEvent).unwrap().into())
+    }
+}
+
+impl Storable for Category {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Name and description are limited in characters, which take up to 4 bytes each
+impl BoundedStorable for Category {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Venue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
+// Text fields are limited in characters, which take up to 4 bytes each
+impl BoundedStorable for Venue {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 impl BoundedStorable for Event {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LegacyEventRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for LegacyEventRecord {
     const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
 // Implement Storable and BoundedStorable  traits for User
 impl Storable for User {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
@@ -138,11 +755,11 @@ impl BoundedStorable for User {
 }
 
 impl Storable for Attendee {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
@@ -153,12 +770,13 @@ impl BoundedStorable for Attendee {
 }
 
 impl Storable for Ticket {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|_| Decode!(bytes.as_ref(), LegacyTicket).unwrap().into())
     }
 }
 
@@ -167,787 +785,5444 @@ impl BoundedStorable for Ticket {
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
-
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
-
-    static EVENTS_STORAGE: RefCell<StableBTreeMap<u64, Event, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+impl Storable for Session {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    static ATTENDEES_STORAGE: RefCell<StableBTreeMap<u64, Attendee, Memory>> =
-    RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-    static TICKETS_STORAGE: RefCell<StableBTreeMap<u64, Ticket, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+impl BoundedStorable for Session {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    static USER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), 0)
-            .expect("Cannot create a counter")
-    );
+impl Storable for PasswordReset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    static USER_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-    ));
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct EventPayload {
-    event_name: String,
-    details: String,
-    location: String,
-    username: String,
-    password: String,
-    start_date: String,
-    end_date: String,
+impl BoundedStorable for PasswordReset {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct AttendeePayload {
-    attendee_name: String,
-}
+impl Storable for PasswordPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct TicketPayload {
-    event_id: u64,
-    ticket_type: TicketType,
-    ticket_price: u64,
-    num_tickets: u32,
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize)]
-struct TicketPurchasePayload {
-    event_id: u64,
-    ticket_type: TicketType,
-    attendee_name: String,
-    num_tickets: u32,
+impl Storable for ApiKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct UserPayload {
-    username: String,
-    email: String,
-    password: String,
-    role: UserRole,
+impl BoundedStorable for ApiKey {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct UserPayload1 {
-    username: String,
-    password: String,
-}
+impl Storable for EventSeries {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-#[ic_cdk::update]
-fn register_user(payload: UserPayload) -> Result<User, Error> {
-    // Validate user payload all fields are required
-    if payload.username.is_empty() || payload.email.is_empty() || payload.password.is_empty() {
-        return Err(Error::CustomError("All fields are required".to_string()));
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    // Hash the password
-    let password = hash_password(&payload.password)?;
+impl BoundedStorable for EventSeries {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let id = USER_ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    let user = User {
-        id,
-        username: payload.username,
-        email: payload.email,
-        password,
-        role: payload.role,
-        created_at: time(),
-        updated_at: None,
-    };
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-    do_insert_user(user.clone());
+impl Storable for EmailVerification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    Ok(user)
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Helper function to hash passwords using SHA-256
-fn hash_password(password: &str) -> Result<String, Error> {
-    let mut hasher = Sha256::new();
-    hasher.update(password);
-    Ok(hex::encode(hasher.finalize()))
+impl BoundedStorable for EmailVerification {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[ic_cdk::query]
-fn get_user(user_id: u64) -> Result<User, Error> {
-    match USER_STORAGE.with(|storage| storage.borrow().get(&user_id)) {
-        Some(user) => Ok(user.clone()),
-        None => Err(Error::NotFound {
-            msg: format!("User with ID {} not found.", user_id),
-        }),
+impl Storable for OutboxMessage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
-}
 
-#[ic_cdk::update]
-fn update_user(user_id: u64, payload: UserPayload, payload2: UserPayload1) -> Result<User, Error> {
-    // Validate user payload: all fields are required
-    if payload.username.is_empty() || payload.email.is_empty() || payload.password.is_empty() {
-        return Err(Error::CustomError("All fields are required".to_string()));
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
+}
 
-    // Clone the username before passing it to authenticate_admin
-    let username = payload2.username.clone();
-    let password = payload2.password.clone();
-
-    let _user = authenticate_user(username, password)?;
+impl BoundedStorable for OutboxMessage {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let updated_user = USER_STORAGE.with(|storage| {
-        let mut user_storage = storage.borrow_mut();
-        if let Some(user) = user_storage.get(&user_id) {
-            let updated_user = User {
-                id: user.id,
-                username: payload.username.clone(),
-                email: payload.email.clone(),
-                password: payload.password.clone(),
-                role: payload.role,
-                created_at: user.created_at,
-                updated_at: Some(time()),
-            };
-            user_storage.insert(user_id, updated_user.clone());
-            Some(updated_user)
-        } else {
-            None
-        }
-    });
+impl Storable for TotpConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    match updated_user {
-        Some(user) => Ok(user),
-        None => Err(Error::NotFound {
-            msg: format!("User with ID {} not found.", user_id),
-        }),
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-#[ic_cdk::update]
-fn delete_user(user_id: u64, payload: UserPayload1) -> Result<User, Error> {
-    // Clone the username before passing it to authenticate_admin
-    let username = payload.username.clone();
-    let password = payload.password.clone();
+impl BoundedStorable for TotpConfig {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let _user = authenticate_user(username, password)?;
-    match USER_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id)) {
-        Some(user) => Ok(user),
-        None => Err(Error::NotFound {
-            msg: format!("User with ID {} not found.", user_id),
-        }),
+impl Storable for LoginAttempts {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-// helper method to perform insert for users.
-fn do_insert_user(user: User) {
-    USER_STORAGE.with(|m| m.borrow_mut().insert(user.id, user));
+impl BoundedStorable for LoginAttempts {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-fn authenticate_admin(username: String, password: String) -> Result<User, Error> {
-    // Find the user by username (assuming username is unique)
-    let user = USER_STORAGE.with(|storage| {
-        let borrowed_storage = storage.borrow();
-        borrowed_storage
-            .iter()
-            .find(|(_, user)| user.username == username)
-            .map(|(_, user)| user.clone())
-    });
+impl Storable for RoleChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-    // Check if the user was found
-    match user {
-        Some(user) => {
-            // Hash the provided password
-            let hashed_password = hash_password(&password)?;
-
-            // Check if the hashed password matches
-            if user.password == hashed_password {
-                // Check if the user's role is admin
-                if user.role == UserRole::Admin {
-                    Ok(user) // Authentication successful
-                } else {
-                    Err(Error::CustomError("Insufficient privileges".to_string()))
-                    // User is not admin
-                }
-            } else {
-                Err(Error::CustomError("Incorrect password".to_string())) // Incorrect password
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: "User not found".to_string(),
-        }), // User not found
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-fn authenticate_user(username: String, password: String) -> Result<User, Error> {
-    // Find the user by username (assuming username is unique)
-    let user = USER_STORAGE.with(|storage| {
-        let borrowed_storage = storage.borrow();
-        borrowed_storage
-            .iter()
-            .find(|(_, user)| user.username == username)
-            .map(|(_, user)| user.clone())
-    });
+impl BoundedStorable for RoleChange {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    // Check if the user was found
-    match user {
-        Some(user) => {
-            // Hash the provided password
-            let hashed_password = hash_password(&password)?;
-
-            // Check if the hashed password matches
-            if user.password == hashed_password {
-                // Check if the user's role is User or Admin
-                if user.role == UserRole::User || user.role == UserRole::Admin {
-                    Ok(user) // Authentication successful
-                } else {
-                    Err(Error::CustomError("Insufficient privileges".to_string()))
-                    // User does not have sufficient privileges
-                }
-            } else {
-                Err(Error::CustomError("Incorrect password".to_string())) // Incorrect password
-            }
-        }
-        None => Err(Error::NotFound {
-            msg: "User not found".to_string(),
-        }), // User not found
+impl Storable for EventStaff {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
     }
 }
 
-// Event Queries
-#[ic_cdk::update]
-fn add_event(event: EventPayload) -> Result<Event, Error> {
-    // Clone the username before passing it to authenticate_admin
-    let username = event.username.clone();
-    let password = event.password.clone();
+impl BoundedStorable for EventStaff {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    let _user = authenticate_admin(username, password)?;
+// Bounded string key used by the secondary indexes
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct StringKey(String);
 
-    // Validate event payload: all fields are required
-    if event.event_name.is_empty()
-        || event.details.is_empty()
-        || event.location.is_empty()
-        || event.start_date.is_empty()
-        || event.end_date.is_empty()
-    {
-        return Err(Error::CustomError("All fields are required".to_string()));
+impl Storable for StringKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter");
-
-    let timestamp = time(); // Get the current timestamp
-
-    // Parse start_date and end_date strings into Date structs
-    let start_date = Date::from_string(&event.start_date)
-        .ok_or_else(|| Error::CustomError("Invalid start date format".to_string()))?;
-    let end_date = Date::from_string(&event.end_date)
-        .ok_or_else(|| Error::CustomError("Invalid end date format".to_string()))?;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        StringKey(String::from_utf8(bytes.to_vec()).unwrap())
+    }
+}
 
-    let event = Event {
-        id,
-        event_name: event.event_name,
-        details: event.details,
-        location: event.location,
-        start_date,
-        end_date,
-        timestamp,
-        attendees: Vec::new(),
-    };
-    do_insert_event(&event);
-    Ok(event)
+impl BoundedStorable for StringKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[ic_cdk::update]
-fn update_event(event_id: u64, payload: EventPayload) -> Result<Event, Error> {
-    // Clone the username before passing it to authenticate_admin
-    let username = payload.username.clone();
-    let password = payload.password.clone();
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
 
-    let _user = authenticate_admin(username, password)?;
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
 
-    // Validate that all fields in the payload are filled
-    if payload.event_name.is_empty()
-        || payload.details.is_empty()
-        || payload.location.is_empty()
-        || payload.start_date.is_empty()
-        || payload.end_date.is_empty()
-    {
-        return Err(Error::CustomError(
-            "All fields in the payload are required".to_string(),
-        ));
-    }
+    // Emptied on upgrade once its events are copied to EVENTS_STORAGE
+    static LEGACY_EVENTS_STORAGE: RefCell<StableBTreeMap<u64, LegacyEventRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
 
-    match EVENTS_STORAGE.with(|service| service.borrow().get(&event_id)) {
-        Some(mut event) => {
-            // Parse start_date and end_date strings into Date structs
-            let start_date = Date::from_string(&payload.start_date)
-                .ok_or_else(|| Error::CustomError("Invalid start date format".to_string()))?;
-            let end_date = Date::from_string(&payload.end_date)
-                .ok_or_else(|| Error::CustomError("Invalid end date format".to_string()))?;
+    static EVENTS_STORAGE: RefCell<StableBTreeMap<u64, Event, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
 
-            event.event_name = payload.event_name;
-            event.details = payload.details;
-            event.location = payload.location;
-            event.start_date = start_date; // Update start_date field with parsed Date
-            event.end_date = end_date; // Update end_date field with parsed Date
+    // (event id, position) -> attendee, in the order they were added
+    static EVENT_ATTENDEES: RefCell<StableBTreeMap<(u64, u64), Attendee, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
 
-            do_insert_event(&event);
-            Ok(event)
-        }
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't update an event with id={}. event not found",
-                event_id
-            ),
-        }),
-    }
-}
+    static ATTENDEES_STORAGE: RefCell<StableBTreeMap<u64, Attendee, Memory>> =
+    RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
 
-#[ic_cdk::update]
-fn delete_event(payload: UserPayload1, event_id: Option<u64>) -> Result<Event, Error> {
-    // Authenticate the user
-    let _user = authenticate_admin(payload.username.clone(), payload.password)?;
+    static TICKETS_STORAGE: RefCell<StableBTreeMap<u64, Ticket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
 
-    // Validate that the event_id is provided
-    let event_id = match event_id {
-        Some(id) => id,
-        None => return Err(Error::CustomError("Event ID must be provided".to_string())),
-    };
+    static USER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), 0)
+            .expect("Cannot create a counter")
+    );
 
-    // Proceed with the deletion as before
-    match EVENTS_STORAGE.with(|service| service.borrow_mut().remove(&event_id)) {
-        Some(event) => Ok(event),
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't delete an event with id={}. event not found.",
-                event_id
-            ),
-        }),
-    }
-}
+    static USER_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
 
-#[ic_cdk::query]
-fn get_event(event_id: u64) -> Result<Event, Error> {
-    match _get_event(&event_id) {
-        Some(event) => Ok(event),
-        None => Err(Error::NotFound {
-            msg: format!("an event with id={} not found", event_id),
-        }),
-    }
-}
+    static PRINCIPAL_INDEX: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
 
-#[ic_cdk::query]
-fn get_event_by_name(event_name: String) -> Result<Option<Event>, Error> {
-    // Validate that the event username is not empty
-    if event_name.is_empty() {
-        return Err(Error::CustomError(
-            "Event name cannot be empty".to_string(),
-        ));
-    }
+    // Lowercased username -> user id
+    static USERNAME_INDEX: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
 
-    let lowercase_name = event_name.to_lowercase();
+    // Lowercased email -> user id
+    static EMAIL_INDEX: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static SESSIONS: RefCell<StableBTreeMap<SessionKey, Session, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // Principals named in the init arguments, promoted to admin when they register
+    static BOOTSTRAP_ADMINS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static ROLE_CHANGES: RefCell<StableBTreeMap<u64, RoleChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    // (event id, user id) -> per-event role
+    static EVENT_STAFF: RefCell<StableBTreeMap<(u64, u64), EventStaff, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // User id -> failed login attempts, kept across username changes
+    static LOGIN_ATTEMPTS: RefCell<StableBTreeMap<u64, LoginAttempts, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    // User id -> pending email verification
+    static EMAIL_VERIFICATIONS: RefCell<StableBTreeMap<u64, EmailVerification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static OUTBOX_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static OUTBOX: RefCell<StableBTreeMap<u64, OutboxMessage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // User id -> two-factor authentication settings
+    static TOTP_CONFIGS: RefCell<StableBTreeMap<u64, TotpConfig, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // Hash of the reset token -> pending password reset
+    static PASSWORD_RESETS: RefCell<StableBTreeMap<SessionKey, PasswordReset, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static PASSWORD_POLICY: RefCell<Cell<PasswordPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), PasswordPolicy::default())
+            .expect("Cannot create the password policy")
+    );
+
+    static API_KEY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static API_KEYS: RefCell<StableBTreeMap<u64, ApiKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static AUDIT_LOG: RefCell<Log<AuditEntry, Memory, Memory>> = RefCell::new(
+        Log::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+        .expect("Cannot create the audit log")
+    );
+
+    static SERIES_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static EVENT_SERIES: RefCell<StableBTreeMap<u64, EventSeries, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // (search term, event id) -> weight of the term in the event
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(StringKey, u64), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    static CATEGORY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CATEGORIES: RefCell<StableBTreeMap<u64, Category, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // (category id, event id) of every categorized event
+    static CATEGORY_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // (tag, event id) of every tagged event
+    static TAG_INDEX: RefCell<StableBTreeMap<(StringKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static VENUE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static VENUES: RefCell<StableBTreeMap<u64, Venue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // (venue id, event id) of every event at a venue
+    static VENUE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // (geohash, venue id) of every venue
+    static GEO_INDEX: RefCell<StableBTreeMap<(StringKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // (event id, ticket id) of every ticket
+    static EVENT_TICKETS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // (start date, event id) of every event
+    static EVENT_DATE_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct InitArgs {
+    // Principals that become admins when they register
+    admins: Vec<Principal>,
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    add_bootstrap_admins(args);
+    start_timers();
+}
+
+// Upgrades take the same arguments, so admins can be bootstrapped on an existing deployment.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    add_bootstrap_admins(args);
+    // Events have to move before anything reads them
+    migrate_event_storage();
+    // Accounts created before the username and email indexes existed are indexed on upgrade
+    backfill_user_indexes();
+    demote_ungranted_admins();
+    backfill_search_index();
+    backfill_geo_index();
+    backfill_ticket_index();
+    backfill_date_index();
+    // Timers don't survive upgrades
+    start_timers();
+}
+
+fn add_bootstrap_admins(args: Option<InitArgs>) {
+    for principal in args.map(|args| args.admins).unwrap_or_default() {
+        BOOTSTRAP_ADMINS.with(|admins| admins.borrow_mut().insert(principal_key(&principal), ()));
+    }
+}
+
+// Accounts could pick their own role before role assignment was locked down. Admins whose
+// role wasn't granted and recorded as a `RoleChange` are reset to `User`, unless their
+// principal is a bootstrap admin. Controllers can grant the role again with `grant_role`.
+fn demote_ungranted_admins() {
+    // The latest recorded role of every user
+    let recorded_roles: BTreeMap<u64, UserRole> = ROLE_CHANGES.with(|changes| {
+        changes
+            .borrow()
+            .iter()
+            .map(|(_, change)| (change.user_id, change.new_role))
+            .collect()
+    });
+    let admins: Vec<User> = USER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, user)| user)
+            .filter(|user| {
+                user.role == UserRole::Admin
+                    && recorded_roles.get(&user.id) != Some(&UserRole::Admin)
+            })
+            .collect()
+    });
+
+    for user in admins {
+        let bootstrap_admin = user.principal.as_ref().is_some_and(|principal| {
+            BOOTSTRAP_ADMINS
+                .with(|admins| admins.borrow_mut().remove(&principal_key(principal)))
+                .is_some()
+        });
+        let role = if bootstrap_admin {
+            UserRole::Admin
+        } else {
+            UserRole::User
+        };
+        let user = do_set_role(user, role, ic_cdk::id());
+        audit_system(
+            "post_upgrade",
+            AuditEntity::User,
+            vec![user.id],
+            format!("role: Admin -> {:?}", user.role),
+        );
+    }
+}
+
+// Copies the events of the first events map, with its 1024 byte bound, to EVENTS_STORAGE and
+// their inline attendees to EVENT_ATTENDEES.
+fn migrate_event_storage() {
+    let records: Vec<(u64, LegacyEventRecord)> =
+        LEGACY_EVENTS_STORAGE.with(|service| service.borrow().iter().collect());
+    if records.is_empty() {
+        return;
+    }
+
+    for (event_id, record) in &records {
+        let event = Event::from_bytes(Cow::Borrowed(&record.0));
+        let attendees = Decode!(&record.0, InlineAttendees)
+            .map(|inline| inline.attendees)
+            .unwrap_or_default();
+        EVENTS_STORAGE.with(|service| service.borrow_mut().insert(*event_id, event));
+        EVENT_ATTENDEES.with(|service| {
+            let mut service = service.borrow_mut();
+            for (position, attendee) in attendees.into_iter().enumerate() {
+                service.insert((*event_id, position as u64), attendee);
+            }
+        });
+    }
+    LEGACY_EVENTS_STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        for (event_id, _) in records {
+            service.remove(&event_id);
+        }
+    });
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(EVENT_COMPLETION_INTERVAL, complete_ended_events);
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct EventPayload {
+    event_name: String,
+    details: String,
+    location: String,
+    // ISO 8601 date or date-time, or the older DD-MM-YYYY format
+    start_date: String,
+    end_date: String,
+    // IANA timezone for dates without an offset, UTC if omitted
+    timezone: Option<String>,
+    category_id: Option<u64>,
+    tags: Option<Vec<String>>,
+    // The location may be left empty for events at a venue
+    venue_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Venue {
+    id: u64,
+    name: String,
+    address: Address,
+    // WGS 84 coordinates in degrees
+    latitude: f64,
+    longitude: f64,
+    // Most tickets any event at the venue can have
+    capacity: u64,
+    accessibility: Accessibility,
+    // Organizer who added the venue and may edit it
+    created_by: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Address {
+    street: String,
+    city: String,
+    region: String,
+    postal_code: String,
+    // ISO 3166-1 alpha-2 code, e.g. "KE"
+    country: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Accessibility {
+    wheelchair_accessible: bool,
+    accessible_restrooms: bool,
+    hearing_loop: bool,
+    notes: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct VenuePayload {
+    name: String,
+    address: Address,
+    latitude: f64,
+    longitude: f64,
+    capacity: u64,
+    accessibility: Accessibility,
+}
+
+// Both ends are optional, in nanoseconds since the Unix epoch
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct DateRange {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct NearbyEvent {
+    event: Event,
+    venue: Venue,
+    distance_km: f64,
+}
+
+// `next_cursor` is the id of the last venue of the page
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct VenuePage {
+    venues: Vec<Venue>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Category {
+    id: u64,
+    name: String,
+    description: String,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CategoryPayload {
+    name: String,
+    description: String,
+}
+
+// A category with the number of listed events in it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CategorySummary {
+    category: Category,
+    event_count: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RecurrencePayload {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    // Date or date-time in the event's timezone, like the event dates
+    until: Option<String>,
+    exceptions: Vec<String>,
+}
+
+// The event dates are those of the first occurrence
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventSeriesPayload {
+    event: EventPayload,
+    recurrence: RecurrencePayload,
+}
+
+// Fields that are changed on every upcoming occurrence of a series
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct EventSeriesUpdatePayload {
+    event_name: String,
+    details: String,
+    location: String,
+    category_id: Option<u64>,
+    tags: Option<Vec<String>>,
+    // The location may be left empty for series at a venue
+    venue_id: Option<u64>,
+}
+
+// Every set field has to match
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct EventFilter {
+    // Only events overlapping this range, in nanoseconds since the Unix epoch
+    from: Option<u64>,
+    to: Option<u64>,
+    // Case-insensitive part of the location
+    location: Option<String>,
+    // Owning organizer or co-organizer
+    organizer_id: Option<u64>,
+    status: Option<EventStatus>,
+    category_id: Option<u64>,
+    tag: Option<String>,
+    venue_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+enum EventSort {
+    #[default]
+    StartDate,
+    StartDateDesc,
+    Name,
+    // Most recently created first
+    Newest,
+}
+
+// `next_cursor` is where the page ended, passed back with the same sort to get the next one
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventPage {
+    events: Vec<Event>,
+    next_cursor: Option<EventCursor>,
+}
+
+// The sort key and id of the last event of a page. The page after it doesn't depend on that
+// event still existing or being unchanged.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct EventCursor {
+    key: EventSortKey,
+    id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+enum EventSortKey {
+    // Start date or creation time, in nanoseconds since the Unix epoch
+    Time(u64),
+    // Lowercase event name
+    Name(String),
+}
+
+// `next_cursor` is the id of the last ticket of the page
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TicketPage {
+    tickets: Vec<Ticket>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DoorTicketPage {
+    tickets: Vec<DoorTicket>,
+    next_cursor: Option<u64>,
+}
+
+// `next_cursor` is the number of attendees returned so far
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AttendeePage {
+    attendees: Vec<Attendee>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct SearchFilters {
+    // Case-insensitive part of the location
+    location: Option<String>,
+    // Only events overlapping this range, in nanoseconds since the Unix epoch
+    from: Option<u64>,
+    to: Option<u64>,
+    status: Option<EventStatus>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SearchResult {
+    event: Event,
+    score: u32,
+}
+
+// One page of results, best matches first
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SearchResults {
+    results: Vec<SearchResult>,
+    // Whether a next page has results
+    has_more: bool,
+    page: u32,
+    page_size: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventSeriesDetails {
+    series: EventSeries,
+    occurrences: Vec<Event>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AttendeePayload {
+    attendee_name: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct TicketPayload {
+    event_id: u64,
+    ticket_type: TicketType,
+    ticket_price: u64,
+    num_tickets: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TicketPurchasePayload {
+    event_id: u64,
+    ticket_type: TicketType,
+    attendee_name: String,
+    num_tickets: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct UserPayload {
+    username: String,
+    email: String,
+    password: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct UserPayload1 {
+    username: String,
+    password: String,
+    // Authenticator or recovery code, required once two-factor authentication is enabled
+    totp_code: Option<String>,
+}
+
+// Returned once when enrolling, the secret and recovery codes can't be retrieved again
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TotpEnrollment {
+    secret: String,
+    provisioning_uri: String,
+    recovery_codes: Vec<String>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct EventStaffPayload {
+    event_id: u64,
+    user_id: u64,
+    role: EventRole,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LoginResponse {
+    token: String,
+    user_id: u64,
+    expires_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ApiKeyPayload {
+    name: String,
+    scopes: Vec<ApiScope>,
+    event_ids: Vec<u64>,
+    expires_at: Option<u64>,
+}
+
+// Returned once when an API key is created, the key itself can't be retrieved again
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct IssuedApiKey {
+    key: String,
+    api_key: ApiKeyInfo,
+}
+
+#[ic_cdk::update]
+async fn register_user(payload: UserPayload) -> Result<UserProfile, Error> {
+    // Validate user payload all fields are required
+    if payload.username.is_empty() || payload.email.is_empty() || payload.password.is_empty() {
+        return Err(Error::CustomError("All fields are required".to_string()));
+    }
+
+    validate_user_fields(&payload)?;
+
+    // The new account is bound to the calling identity
+    let principal = caller_principal()?;
+    if find_user_by_principal(&principal).is_some() {
+        return Err(Error::AlreadyExists {
+            msg: format!("Principal {} is already linked to a user", principal),
+        });
+    }
+    ensure_unique_user(&payload.username, &payload.email, None)?;
+
+    // Hash the password
+    let password = hash_password(&payload.password).await?;
+
+    // Check again, the principal, username or email may have been taken while waiting for the salt
+    if find_user_by_principal(&principal).is_some() {
+        return Err(Error::AlreadyExists {
+            msg: format!("Principal {} is already linked to a user", principal),
+        });
+    }
+    ensure_unique_user(&payload.username, &payload.email, None)?;
+
+    let id = USER_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+
+    let user = User {
+        id,
+        username: payload.username,
+        email: payload.email,
+        password,
+        role: UserRole::User,
+        created_at: time(),
+        updated_at: None,
+        principal: Some(principal),
+        email_verified_at: None,
+    };
+
+    do_insert_user(user.clone());
+
+    // Principals named in the init arguments are promoted once they register
+    let bootstrap_admin =
+        BOOTSTRAP_ADMINS.with(|admins| admins.borrow_mut().remove(&principal_key(&principal)));
+    let user = if bootstrap_admin.is_some() {
+        do_set_role(user, UserRole::Admin, ic_cdk::id())
+    } else {
+        user
+    };
+
+    audit(
+        Some(user.id),
+        "register_user",
+        AuditEntity::User,
+        vec![user.id],
+        format!("registered '{}' with role {:?}", user.username, user.role),
+    );
+
+    // The account exists from here on, a code that couldn't be sent can be requested again
+    let user = match issue_email_verification(user.clone()).await {
+        Ok(user) => user,
+        Err(err) => {
+            ic_cdk::print(format!(
+                "Verification email for user {} failed: {:?}",
+                user.id, err
+            ));
+            user
+        }
+    };
+    Ok(user.to_profile(true))
+}
+
+// Links the caller's principal to an existing username/password account.
+// This is the one-time migration path for accounts created before principal authentication.
+#[ic_cdk::update]
+async fn link_principal(payload: UserPayload1) -> Result<UserProfile, Error> {
+    let principal = caller_principal()?;
+    let mut user = authenticate_credentials(&payload).await?;
+
+    if find_user_by_principal(&principal).is_some() {
+        return Err(Error::AlreadyExists {
+            msg: format!("Principal {} is already linked to a user", principal),
+        });
+    }
+    if user.principal.is_some() {
+        return Err(Error::AlreadyExists {
+            msg: format!("User {} is already linked to a principal", user.username),
+        });
+    }
+
+    user.principal = Some(principal);
+    user.updated_at = Some(time());
+    do_insert_user(user.clone());
+
+    audit(
+        Some(user.id),
+        "link_principal",
+        AuditEntity::User,
+        vec![user.id],
+        format!("linked principal {}", principal),
+    );
+    Ok(user.to_profile(true))
+}
+
+// Issues a session token for a username/password account.
+// The token is passed as `session_token` to authenticated endpoints instead of the credentials.
+#[ic_cdk::update]
+async fn login(payload: UserPayload1) -> Result<LoginResponse, Error> {
+    let user = authenticate_credentials(&payload).await?;
+    start_session(user, "login").await
+}
+
+// Starts a session for the calling principal. Principals of users with two-factor authentication
+// enabled are only accepted through the session this returns, after checking `totp_code`.
+#[ic_cdk::update]
+async fn login_principal(totp_code: Option<String>) -> Result<LoginResponse, Error> {
+    let principal = caller_principal()?;
+    let user = find_user_by_principal(&principal).ok_or_else(|| Error::NotFound {
+        msg: format!("No user is linked to principal {}", principal),
+    })?;
+    check_login_lockout(user.id)?;
+    if let Err(err) = verify_second_factor(&user, totp_code) {
+        record_failed_login(user.id);
+        return Err(err);
+    }
+    LOGIN_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&user.id));
+    start_session(user, "login_principal").await
+}
+
+// helper method to start a session for an authenticated user.
+async fn start_session(user: User, method: &str) -> Result<LoginResponse, Error> {
+    let token = hex::encode(random_bytes(SESSION_TOKEN_LEN).await?);
+    let created_at = time();
+    let session = Session {
+        user_id: user.id,
+        created_at,
+        expires_at: created_at + SESSION_TTL_NANOS,
+    };
+    SESSIONS.with(|sessions| {
+        sessions
+            .borrow_mut()
+            .insert(session_key(&token), session.clone())
+    });
+
+    audit(
+        Some(user.id),
+        method,
+        AuditEntity::User,
+        vec![user.id],
+        "started a session",
+    );
+    Ok(LoginResponse {
+        token,
+        user_id: user.id,
+        expires_at: session.expires_at,
+    })
+}
+
+#[ic_cdk::update]
+fn logout(session_token: String) -> Result<(), Error> {
+    match SESSIONS.with(|sessions| sessions.borrow_mut().remove(&session_key(&session_token))) {
+        Some(session) => {
+            audit(
+                Some(session.user_id),
+                "logout",
+                AuditEntity::User,
+                vec![session.user_id],
+                "ended a session",
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: "Session not found".to_string(),
+        }),
+    }
+}
+
+// Ends every session of the authenticated user, returns the number of revoked sessions.
+#[ic_cdk::update]
+fn revoke_all_sessions(session_token: Option<String>) -> Result<u64, Error> {
+    let user = authenticate_user(session_token)?;
+    let revoked = do_revoke_sessions(user.id);
+    audit(
+        Some(user.id),
+        "revoke_all_sessions",
+        AuditEntity::User,
+        vec![user.id],
+        format!("ended {} sessions", revoked),
+    );
+    Ok(revoked)
+}
+
+// Confirms the email of the authenticated user with the code sent to it.
+#[ic_cdk::update]
+fn verify_email(code: String, session_token: Option<String>) -> Result<UserProfile, Error> {
+    let mut user = authenticate_user(session_token)?;
+    let mut verification = EMAIL_VERIFICATIONS
+        .with(|verifications| verifications.borrow().get(&user.id))
+        .filter(|verification| verification.email == user.email)
+        .ok_or_else(|| Error::NotFound {
+            msg: "No email verification is pending".to_string(),
+        })?;
+
+    if verification.expires_at <= time() || verification.attempts >= MAX_EMAIL_CODE_ATTEMPTS {
+        return Err(Error::CustomError(
+            "Verification code has expired, request a new one".to_string(),
+        ));
+    }
+
+    if !constant_time_eq(
+        hex::encode(Sha256::digest(code.trim().as_bytes())).as_bytes(),
+        verification.code_hash.as_bytes(),
+    ) {
+        verification.attempts += 1;
+        EMAIL_VERIFICATIONS
+            .with(|verifications| verifications.borrow_mut().insert(user.id, verification));
+        return Err(Error::CustomError("Invalid verification code".to_string()));
+    }
+
+    EMAIL_VERIFICATIONS.with(|verifications| verifications.borrow_mut().remove(&user.id));
+    user.email_verified_at = Some(time());
+    do_insert_user(user.clone());
+    audit(
+        Some(user.id),
+        "verify_email",
+        AuditEntity::User,
+        vec![user.id],
+        format!("verified email {:?}", user.email),
+    );
+    Ok(user.to_profile(true))
+}
+
+// Sends a new verification code to the email of the authenticated user.
+#[ic_cdk::update]
+async fn resend_verification_email(session_token: Option<String>) -> Result<(), Error> {
+    let user = authenticate_user(session_token)?;
+    if user.email_verified_at.is_some() {
+        return Err(Error::AlreadyExists {
+            msg: "Email is already verified".to_string(),
+        });
+    }
+    check_email_resend_cooldown(user.id)?;
+    let user = issue_email_verification(user).await?;
+    audit(
+        Some(user.id),
+        "resend_verification_email",
+        AuditEntity::User,
+        vec![user.id],
+        format!("sent a new verification code to {:?}", user.email),
+    );
+    Ok(())
+}
+
+// Fails while the last verification code of a user is too recent to send another one.
+fn check_email_resend_cooldown(user_id: u64) -> Result<(), Error> {
+    let Some(verification) =
+        EMAIL_VERIFICATIONS.with(|verifications| verifications.borrow().get(&user_id))
+    else {
+        return Ok(());
+    };
+    let doublings = verification.resends.unwrap_or(0).min(32);
+    let cooldown = EMAIL_RESEND_BASE_NANOS
+        .saturating_mul(1 << doublings)
+        .min(EMAIL_RESEND_MAX_NANOS);
+    let next_at = verification.sent_at.unwrap_or(0).saturating_add(cooldown);
+    if next_at > time() {
+        return Err(Error::CustomError(format!(
+            "A verification code was sent recently, try again in {} seconds",
+            (next_at - time()).div_ceil(1_000_000_000)
+        )));
+    }
+    Ok(())
+}
+
+// Generates a verification code for the user's email and queues it in the outbox.
+async fn issue_email_verification(user: User) -> Result<User, Error> {
+    let bytes = random_bytes(4).await?;
+    let code = format!(
+        "{:0width$}",
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % 10u32.pow(EMAIL_CODE_DIGITS),
+        width = EMAIL_CODE_DIGITS as usize
+    );
+
+    // Re-read the account, the email may have changed while waiting for randomness
+    let user = load_user(user.id)?;
+    EMAIL_VERIFICATIONS.with(|verifications| {
+        let mut verifications = verifications.borrow_mut();
+        // Every code sent lengthens the wait for the next one, so codes can't be guessed by
+        // requesting new ones
+        let resends = verifications.get(&user.id).map_or(0, |previous| {
+            previous.resends.unwrap_or(0).saturating_add(1)
+        });
+        verifications.insert(
+            user.id,
+            EmailVerification {
+                email: user.email.clone(),
+                code_hash: hex::encode(Sha256::digest(code.as_bytes())),
+                expires_at: time() + EMAIL_CODE_TTL_NANOS,
+                attempts: 0,
+                sent_at: Some(time()),
+                resends: Some(resends),
+            },
+        )
+    });
+    send_email(
+        &user.email,
+        "Verify your email",
+        &format!(
+            "Hi {}, your Event Sphere verification code is {}",
+            user.username, code
+        ),
+    );
+    Ok(user)
+}
+
+// Queues an email in the outbox. Delivery is left to an off-chain relay that polls
+// `get_outbox` and acknowledges with `ack_outbox`, locally the queue can be read directly.
+fn send_email(recipient: &str, subject: &str, body: &str) {
+    let id = OUTBOX_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    let message = OutboxMessage {
+        id,
+        recipient: recipient.to_string(),
+        subject: subject.to_string(),
+        body: body.to_string(),
+        created_at: time(),
+    };
+    OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, message));
+}
+
+#[ic_cdk::query]
+fn get_outbox(session_token: Option<String>) -> Result<Vec<OutboxMessage>, Error> {
+    authorize_controller_or(session_token, Permission::ManageOutbox)?;
+    Ok(OUTBOX.with(|outbox| outbox.borrow().iter().map(|(_, message)| message).collect()))
+}
+
+// Removes delivered messages from the outbox.
+#[ic_cdk::update]
+fn ack_outbox(message_ids: Vec<u64>, session_token: Option<String>) -> Result<(), Error> {
+    let user = authorize_controller_or(session_token, Permission::ManageOutbox)?;
+    let removed: Vec<u64> = OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        message_ids
+            .into_iter()
+            .filter(|id| outbox.remove(id).is_some())
+            .collect()
+    });
+    audit(
+        user.map(|user| user.id),
+        "ack_outbox",
+        AuditEntity::Outbox,
+        removed.clone(),
+        format!("acknowledged {} delivered messages", removed.len()),
+    );
+    Ok(())
+}
+
+// Changes the password of the authenticated user, ending all of their sessions.
+#[ic_cdk::update]
+async fn change_password(
+    old_password: String,
+    new_password: String,
+    session_token: Option<String>,
+) -> Result<(), Error> {
+    validate_password(&new_password)?;
+    let user = authenticate_user(session_token)?;
+    // A locked account can't be used to keep guessing through a stolen session
+    check_login_lockout(user.id)?;
+    if !verify_password(&old_password, &user.password) {
+        // Wrong guesses count towards the login lockout of the account
+        record_failed_login(user.id);
+        return Err(Error::CustomError("Invalid password".to_string()));
+    }
+
+    let password = hash_password(&new_password).await?;
+    do_set_password(user.id, password)?;
+    audit(
+        Some(user.id),
+        "change_password",
+        AuditEntity::User,
+        vec![user.id],
+        "changed password",
+    );
+    Ok(())
+}
+
+// Emails a single-use password reset token to the account with this email. Succeeds
+// whether or not an account exists, and takes the same path until the lookup, so registered
+// emails can't be probed. An account gets at most one email every few minutes.
+#[ic_cdk::update]
+async fn request_password_reset(email: String) -> Result<(), Error> {
+    let token = hex::encode(random_bytes(RESET_TOKEN_LEN).await?);
+
+    if email.len() > MAX_EMAIL_LEN {
+        return Ok(());
+    }
+    let Some(user) = EMAIL_INDEX
+        .with(|index| index.borrow().get(&index_key(&email)))
+        .and_then(|user_id| load_user(user_id).ok())
+    else {
+        return Ok(());
+    };
+    let now = time();
+    let issued = PASSWORD_RESETS.with(|resets| {
+        let mut resets = resets.borrow_mut();
+        let recent = resets.iter().any(|(_, reset)| {
+            reset.user_id == user.id
+                && reset.expires_at > now
+                && reset.expires_at - RESET_TOKEN_TTL_NANOS + RESET_REQUEST_COOLDOWN_NANOS > now
+        });
+        if recent {
+            return false;
+        }
+        // Only the latest token of an account stays valid
+        let stale: Vec<SessionKey> = resets
+            .iter()
+            .filter(|(_, reset)| reset.user_id == user.id || reset.expires_at <= now)
+            .map(|(key, _)| key)
+            .collect();
+        for key in stale {
+            resets.remove(&key);
+        }
+        resets.insert(
+            session_key(&token),
+            PasswordReset {
+                user_id: user.id,
+                expires_at: now + RESET_TOKEN_TTL_NANOS,
+            },
+        );
+        true
+    });
+    if !issued {
+        return Ok(());
+    }
+    send_email(
+        &user.email,
+        "Reset your password",
+        &format!(
+            "Hi {}, use this token within an hour to reset your Event Sphere password: {}",
+            user.username, token
+        ),
+    );
+    audit(
+        None,
+        "request_password_reset",
+        AuditEntity::User,
+        vec![user.id],
+        "emailed a password reset token",
+    );
+    Ok(())
+}
+
+// Sets a new password with a token from `request_password_reset`, ending all sessions.
+#[ic_cdk::update]
+async fn reset_password(reset_token: String, new_password: String) -> Result<(), Error> {
+    validate_password(&new_password)?;
+    // Tokens are consumed by the first attempt
+    let reset = PASSWORD_RESETS
+        .with(|resets| resets.borrow_mut().remove(&session_key(&reset_token)))
+        .filter(|reset| reset.expires_at > time())
+        .ok_or_else(|| Error::CustomError("Invalid or expired reset token".to_string()))?;
+
+    let password = hash_password(&new_password).await?;
+    do_set_password(reset.user_id, password)?;
+
+    // Proving ownership of the email also lifts a lockout from failed logins
+    let user = load_user(reset.user_id)?;
+    LOGIN_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&user.id));
+    audit(
+        Some(user.id),
+        "reset_password",
+        AuditEntity::User,
+        vec![user.id],
+        "reset password with an emailed token",
+    );
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_password_policy() -> PasswordPolicy {
+    PASSWORD_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_password_policy(policy: PasswordPolicy, session_token: Option<String>) -> Result<(), Error> {
+    let user = authorize_controller_or(session_token, Permission::ManageUsers)?;
+    if policy.min_length as usize > MAX_PASSWORD_LEN {
+        return Err(Error::CustomError(format!(
+            "Minimum length cannot be more than {}",
+            MAX_PASSWORD_LEN
+        )));
+    }
+    let previous = get_password_policy();
+    let summary = diff_summary(&[
+        (
+            "min_length",
+            previous.min_length.to_string(),
+            policy.min_length.to_string(),
+        ),
+        (
+            "require_uppercase",
+            previous.require_uppercase.to_string(),
+            policy.require_uppercase.to_string(),
+        ),
+        (
+            "require_lowercase",
+            previous.require_lowercase.to_string(),
+            policy.require_lowercase.to_string(),
+        ),
+        (
+            "require_digit",
+            previous.require_digit.to_string(),
+            policy.require_digit.to_string(),
+        ),
+        (
+            "require_symbol",
+            previous.require_symbol.to_string(),
+            policy.require_symbol.to_string(),
+        ),
+    ]);
+    PASSWORD_POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .expect("cannot update the password policy");
+    audit(
+        user.map(|user| user.id),
+        "set_password_policy",
+        AuditEntity::Settings,
+        vec![],
+        summary,
+    );
+    Ok(())
+}
+
+// helper method to store a new password hash and end the sessions of the account.
+fn do_set_password(user_id: u64, password: String) -> Result<(), Error> {
+    // Re-read the account, it may have changed while hashing
+    let mut user = load_user(user_id)?;
+    user.password = password;
+    user.updated_at = Some(time());
+    do_insert_user(user);
+    do_revoke_sessions(user_id);
+    Ok(())
+}
+
+// Checks a new password against the length limit and the password policy.
+fn validate_password(password: &str) -> Result<(), Error> {
+    if password.len() > MAX_PASSWORD_LEN {
+        return Err(Error::CustomError(format!(
+            "Password cannot be longer than {} bytes",
+            MAX_PASSWORD_LEN
+        )));
+    }
+
+    let policy = get_password_policy();
+    let mut missing = Vec::new();
+    if password.chars().count() < policy.min_length as usize {
+        missing.push(format!("at least {} characters", policy.min_length));
+    }
+    let checks = [
+        (
+            policy.require_uppercase,
+            "an uppercase letter",
+            char::is_uppercase as fn(char) -> bool,
+        ),
+        (
+            policy.require_lowercase,
+            "a lowercase letter",
+            char::is_lowercase,
+        ),
+        (policy.require_digit, "a digit", |c: char| {
+            c.is_ascii_digit()
+        }),
+        (policy.require_symbol, "a symbol", |c: char| {
+            !c.is_alphanumeric() && !c.is_whitespace()
+        }),
+    ];
+    for (required, description, check) in checks {
+        if required && !password.chars().any(check) {
+            missing.push(description.to_string());
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::CustomError(format!(
+            "Password must contain {}",
+            missing.join(", ")
+        )))
+    }
+}
+
+// Starts two-factor enrollment for the authenticated user. The returned secret and recovery
+// codes are only shown once, the enrollment takes effect after `confirm_totp`.
+#[ic_cdk::update]
+async fn enroll_totp(session_token: Option<String>) -> Result<TotpEnrollment, Error> {
+    let user = authenticate_user(session_token)?;
+    if !matches!(user.role, UserRole::Admin | UserRole::Organizer) {
+        return Err(Error::CustomError(
+            "Two-factor authentication is available to admins and organizers".to_string(),
+        ));
+    }
+    if get_totp_config(user.id).is_some_and(|config| config.enabled) {
+        return Err(Error::AlreadyExists {
+            msg: "Two-factor authentication is already enabled".to_string(),
+        });
+    }
+
+    let bytes = random_bytes(TOTP_SECRET_LEN + TOTP_RECOVERY_CODES * 5).await?;
+    let (secret, recovery_bytes) = bytes.split_at(TOTP_SECRET_LEN);
+    let recovery_codes: Vec<String> = recovery_bytes
+        .chunks(5)
+        .map(|chunk| {
+            let code = hex::encode(chunk);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    TOTP_CONFIGS.with(|configs| {
+        configs.borrow_mut().insert(
+            user.id,
+            TotpConfig {
+                secret: secret.to_vec(),
+                enabled: false,
+                recovery_code_hashes: recovery_codes
+                    .iter()
+                    .map(|code| hash_recovery_code(code))
+                    .collect(),
+                last_used_step: 0,
+                created_at: time(),
+            },
+        )
+    });
+
+    audit(
+        Some(user.id),
+        "enroll_totp",
+        AuditEntity::User,
+        vec![user.id],
+        "started two-factor enrollment",
+    );
+    let secret = base32_encode(secret);
+    Ok(TotpEnrollment {
+        provisioning_uri: format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = TOTP_ISSUER,
+            account = percent_encode(&user.username),
+            secret = secret,
+            digits = TOTP_DIGITS,
+            period = TOTP_PERIOD_SECS,
+        ),
+        secret,
+        recovery_codes,
+    })
+}
+
+// Enables two-factor authentication with a first code from the authenticator app.
+#[ic_cdk::update]
+fn confirm_totp(code: String, session_token: Option<String>) -> Result<(), Error> {
+    let user = authenticate_user(session_token)?;
+    let mut config = get_totp_config(user.id).ok_or_else(|| Error::NotFound {
+        msg: "Two-factor authentication has not been enrolled".to_string(),
+    })?;
+    if config.enabled {
+        return Err(Error::AlreadyExists {
+            msg: "Two-factor authentication is already enabled".to_string(),
+        });
+    }
+
+    let step = verify_totp_code(&config, &code)
+        .ok_or_else(|| Error::CustomError("Invalid authentication code".to_string()))?;
+    config.enabled = true;
+    config.last_used_step = step;
+    TOTP_CONFIGS.with(|configs| configs.borrow_mut().insert(user.id, config));
+    // Sessions started without a code end, the next login asks for one
+    let revoked = do_revoke_sessions(user.id);
+    audit(
+        Some(user.id),
+        "confirm_totp",
+        AuditEntity::User,
+        vec![user.id],
+        format!(
+            "enabled two-factor authentication and ended {} sessions",
+            revoked
+        ),
+    );
+    Ok(())
+}
+
+// Turns two-factor authentication off, requires a current authenticator or recovery code.
+#[ic_cdk::update]
+fn disable_totp(code: String, session_token: Option<String>) -> Result<(), Error> {
+    let user = authenticate_user(session_token)?;
+    verify_second_factor(&user, Some(code))?;
+    TOTP_CONFIGS.with(|configs| configs.borrow_mut().remove(&user.id));
+    audit(
+        Some(user.id),
+        "disable_totp",
+        AuditEntity::User,
+        vec![user.id],
+        "disabled two-factor authentication",
+    );
+    Ok(())
+}
+
+// a helper method to get the two-factor settings of a user.
+fn get_totp_config(user_id: u64) -> Option<TotpConfig> {
+    TOTP_CONFIGS.with(|configs| configs.borrow().get(&user_id))
+}
+
+fn totp_enabled(user_id: u64) -> bool {
+    get_totp_config(user_id).is_some_and(|config| config.enabled)
+}
+
+// Checks the second factor of users with two-factor authentication enabled. Accepts a code
+// from the authenticator app or an unused recovery code, which is consumed.
+fn verify_second_factor(user: &User, code: Option<String>) -> Result<(), Error> {
+    let mut config = match get_totp_config(user.id) {
+        Some(config) if config.enabled => config,
+        _ => return Ok(()),
+    };
+    let code = code.ok_or_else(|| {
+        Error::CustomError("Two-factor authentication code is required".to_string())
+    })?;
+
+    if let Some(step) = verify_totp_code(&config, &code) {
+        config.last_used_step = step;
+    } else {
+        let code_hash = hash_recovery_code(&code);
+        let unused = config.recovery_code_hashes.len();
+        config
+            .recovery_code_hashes
+            .retain(|hash| *hash != code_hash);
+        if config.recovery_code_hashes.len() == unused {
+            return Err(Error::CustomError(
+                "Invalid authentication code".to_string(),
+            ));
+        }
+    }
+
+    TOTP_CONFIGS.with(|configs| configs.borrow_mut().insert(user.id, config));
+    Ok(())
+}
+
+// Returns the time step a code matches, codes at or before the last used step are rejected.
+fn verify_totp_code(config: &TotpConfig, code: &str) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+
+    let current_step = time() / 1_000_000_000 / TOTP_PERIOD_SECS;
+    (current_step.saturating_sub(TOTP_ALLOWED_DRIFT_STEPS)
+        ..=current_step + TOTP_ALLOWED_DRIFT_STEPS)
+        .filter(|step| *step > config.last_used_step)
+        .find(|step| {
+            let expected = format!(
+                "{:0width$}",
+                hotp(&config.secret, *step),
+                width = TOTP_DIGITS as usize
+            );
+            constant_time_eq(expected.as_bytes(), code.as_bytes())
+        })
+}
+
+// RFC 4226 HOTP value for a counter, using HMAC-SHA1 and dynamic truncation.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.trim().to_lowercase().as_bytes()))
+}
+
+// RFC 4648 base32 without padding, the format authenticator apps expect for secrets.
+fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Helper function to hash passwords using PBKDF2-HMAC-SHA256 with a fresh random salt
+async fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = random_bytes(PASSWORD_SALT_LEN).await?;
+    Ok(hash_password_with_salt(
+        password,
+        &salt,
+        PASSWORD_HASH_ITERATIONS,
+    ))
+}
+
+fn hash_password_with_salt(password: &str, salt: &[u8], iterations: u32) -> String {
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+    format!(
+        "{}${}${}${}",
+        PASSWORD_HASH_SCHEME,
+        iterations,
+        hex::encode(salt),
+        hex::encode(hash)
+    )
+}
+
+// Checks a password against a stored hash of any supported version.
+fn verify_password(password: &str, stored_hash: &str) -> bool {
+    let parts: Vec<&str> = stored_hash.split('$').collect();
+    let computed = match parts.as_slice() {
+        [scheme, iterations, salt, _] if *scheme == PASSWORD_HASH_SCHEME => {
+            match (iterations.parse::<u32>(), hex::decode(salt)) {
+                (Ok(iterations), Ok(salt)) => hash_password_with_salt(password, &salt, iterations),
+                _ => return false,
+            }
+        }
+        // Legacy unsalted SHA-256 hash
+        [_] => hex::encode(Sha256::digest(password.as_bytes())),
+        _ => return false,
+    };
+    constant_time_eq(computed.as_bytes(), stored_hash.as_bytes())
+}
+
+// Legacy hashes and hashes with fewer iterations than the current setting get upgraded on login.
+fn password_needs_rehash(stored_hash: &str) -> bool {
+    let parts: Vec<&str> = stored_hash.split('$').collect();
+    match parts.as_slice() {
+        [scheme, iterations, _, _] if *scheme == PASSWORD_HASH_SCHEME => iterations
+            .parse::<u32>()
+            .map_or(true, |i| i < PASSWORD_HASH_ITERATIONS),
+        _ => true,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Fetches cryptographically secure random bytes from the management canister.
+// Each call yields 32 bytes, more are gathered over several calls.
+async fn random_bytes(len: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::with_capacity(len);
+    while bytes.len() < len {
+        let (chunk,) = raw_rand()
+            .await
+            .map_err(|(_, msg)| Error::CustomError(format!("Failed to get randomness: {}", msg)))?;
+        if chunk.is_empty() {
+            return Err(Error::CustomError(
+                "Not enough randomness available".to_string(),
+            ));
+        }
+        bytes.extend(chunk);
+    }
+    bytes.truncate(len);
+    Ok(bytes)
+}
+
+// Anyone may look up a profile, the email is only included for the owner and admins.
+#[ic_cdk::query]
+fn get_user(user_id: u64, session_token: Option<String>) -> Result<UserProfile, Error> {
+    let user = load_user(user_id)?;
+    let include_private = authenticate_user(session_token)
+        .is_ok_and(|viewer| has_permission(&viewer, Permission::ManageAccount(user_id)));
+    Ok(user.to_profile(include_private))
+}
+
+// a helper method to get a user by id.
+fn load_user(user_id: u64) -> Result<User, Error> {
+    match USER_STORAGE.with(|storage| storage.borrow().get(&user_id)) {
+        Some(user) => Ok(user),
+        None => Err(Error::NotFound {
+            msg: format!("User with ID {} not found.", user_id),
+        }),
+    }
+}
+
+// Updates a profile, only the account owner or an admin may do so.
+// The role is kept as is, roles only change through `grant_role` and `revoke_role`, and the
+// password through `change_password` and `reset_password`.
+#[ic_cdk::update]
+async fn update_user(
+    user_id: u64,
+    payload: UserPayload,
+    session_token: Option<String>,
+) -> Result<UserProfile, Error> {
+    // Validate user payload: username and email are required
+    if payload.username.is_empty() || payload.email.is_empty() {
+        return Err(Error::CustomError("All fields are required".to_string()));
+    }
+    if !payload.password.is_empty() {
+        return Err(Error::CustomError(
+            "Passwords can only be changed with change_password or reset_password".to_string(),
+        ));
+    }
+    validate_profile_fields(&payload.username, &payload.email)?;
+
+    let editor = authorize(session_token, Permission::ManageAccount(user_id))?;
+    let user = load_user(user_id)?;
+    // A locked account can't be edited until the lock expires or an admin lifts it
+    check_login_lockout(user_id)?;
+    ensure_unique_user(&payload.username, &payload.email, Some(user_id))?;
+    let email_changed = index_key(&user.email) != index_key(&payload.email);
+    // A changed email gets a new code, as limited as resending one
+    if email_changed {
+        check_email_resend_cooldown(user_id)?;
+    }
+
+    let updated_user = User {
+        id: user.id,
+        username: payload.username,
+        email: payload.email,
+        password: user.password.clone(),
+        role: user.role,
+        created_at: user.created_at,
+        updated_at: Some(time()),
+        principal: user.principal,
+        // A changed email has to be verified again
+        email_verified_at: user.email_verified_at.filter(|_| !email_changed),
+    };
+    do_insert_user(updated_user.clone());
+    audit(
+        Some(editor.id),
+        "update_user",
+        AuditEntity::User,
+        vec![user_id],
+        diff_summary(&[
+            ("username", user.username, updated_user.username.clone()),
+            ("email", user.email, updated_user.email.clone()),
+        ]),
+    );
+
+    let updated_user = if email_changed {
+        issue_email_verification(updated_user).await?
+    } else {
+        updated_user
+    };
+    Ok(updated_user.to_profile(true))
+}
+
+// Deletes an account, only the account owner or an admin may do so.
+#[ic_cdk::update]
+fn delete_user(user_id: u64, session_token: Option<String>) -> Result<UserProfile, Error> {
+    let editor = authorize(session_token, Permission::ManageAccount(user_id))?;
+    match do_remove_user(user_id) {
+        Some(user) => {
+            do_revoke_sessions(user.id);
+            audit(
+                Some(editor.id),
+                "delete_user",
+                AuditEntity::User,
+                vec![user.id],
+                format!("deleted '{}'", user.username),
+            );
+            Ok(user.to_profile(true))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("User with ID {} not found.", user_id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn grant_role(
+    user_id: u64,
+    role: UserRole,
+    session_token: Option<String>,
+) -> Result<UserProfile, Error> {
+    let editor = authorize_controller_or(session_token, Permission::ManageRoles)?;
+    let user = load_user(user_id)?;
+    let previous_role = user.role.clone();
+    let user = do_set_role(user, role, caller());
+    audit(
+        editor.map(|editor| editor.id),
+        "grant_role",
+        AuditEntity::User,
+        vec![user.id],
+        format!("role: {:?} -> {:?}", previous_role, user.role),
+    );
+    Ok(user.to_profile(true))
+}
+
+// Resets a user back to the default `User` role.
+#[ic_cdk::update]
+fn revoke_role(user_id: u64, session_token: Option<String>) -> Result<UserProfile, Error> {
+    let editor = authorize_controller_or(session_token, Permission::ManageRoles)?;
+    let user = load_user(user_id)?;
+    let previous_role = user.role.clone();
+    let user = do_set_role(user, UserRole::User, caller());
+    audit(
+        editor.map(|editor| editor.id),
+        "revoke_role",
+        AuditEntity::User,
+        vec![user.id],
+        format!("role: {:?} -> {:?}", previous_role, user.role),
+    );
+    Ok(user.to_profile(true))
+}
+
+#[ic_cdk::query]
+fn get_role_changes(
+    user_id: Option<u64>,
+    session_token: Option<String>,
+) -> Result<Vec<RoleChange>, Error> {
+    authorize_controller_or(session_token, Permission::ManageRoles)?;
+    Ok(ROLE_CHANGES.with(|changes| {
+        changes
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| user_id.is_none_or(|id| change.user_id == id))
+            .collect()
+    }))
+}
+
+// Canister controllers pass without an account, everyone else needs the permission.
+// Used for roles and the outbox so a deployment can be administered before any admin exists.
+// Returns the authorized user, None for controllers.
+fn authorize_controller_or(
+    session_token: Option<String>,
+    permission: Permission,
+) -> Result<Option<User>, Error> {
+    if is_controller(&caller()) {
+        return Ok(None);
+    }
+    authorize(session_token, permission).map(Some)
+}
+
+// Lists the audit log newest first, filtered by entity, target id or acting user.
+// A page may hold fewer entries than the limit, only the absent cursor marks the end.
+#[ic_cdk::query]
+fn get_audit_log(
+    filter: AuditFilter,
+    cursor: Option<u64>,
+    limit: u32,
+    session_token: Option<String>,
+) -> Result<AuditPage, Error> {
+    authorize_controller_or(session_token, Permission::ViewAuditLog)?;
+    let limit = limit.clamp(1, MAX_AUDIT_PAGE_SIZE) as usize;
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let mut entries = Vec::new();
+        let mut index = cursor.unwrap_or(log.len()).min(log.len());
+        let scan_end = index.saturating_sub(MAX_AUDIT_SCAN);
+        while index > scan_end && entries.len() < limit {
+            index -= 1;
+            let entry = log.get(index).expect("audit entries are never removed");
+            if filter.entity.is_none_or(|entity| entry.entity == entity)
+                && filter
+                    .target_id
+                    .is_none_or(|target_id| entry.target_ids.contains(&target_id))
+                && filter
+                    .user_id
+                    .is_none_or(|user_id| entry.user_id == Some(user_id))
+            {
+                entries.push(entry);
+            }
+        }
+        Ok(AuditPage {
+            entries,
+            next_cursor: Some(index).filter(|index| *index > 0),
+        })
+    })
+}
+
+// Appends an entry to the audit log, called by every update method once its change is made.
+fn audit(
+    user_id: Option<u64>,
+    method: &str,
+    entity: AuditEntity,
+    target_ids: Vec<u64>,
+    summary: impl Into<String>,
+) {
+    audit_entry(
+        caller(),
+        user_id,
+        None,
+        method,
+        entity,
+        target_ids,
+        summary.into(),
+    );
+}
+
+// Records a change made by a user or with a partner's API key.
+fn audit_caller(
+    actor: &Caller,
+    method: &str,
+    entity: AuditEntity,
+    target_ids: Vec<u64>,
+    summary: impl Into<String>,
+) {
+    let (user_id, api_key_id) = match actor {
+        Caller::User(user) => (Some(user.id), None),
+        Caller::ApiKey(key) => (None, Some(key.id)),
+    };
+    audit_entry(
+        caller(),
+        user_id,
+        api_key_id,
+        method,
+        entity,
+        target_ids,
+        summary.into(),
+    );
+}
+
+// Records a change the canister made on its own, e.g. from a timer.
+fn audit_system(
+    method: &str,
+    entity: AuditEntity,
+    target_ids: Vec<u64>,
+    summary: impl Into<String>,
+) {
+    audit_entry(
+        ic_cdk::id(),
+        None,
+        None,
+        method,
+        entity,
+        target_ids,
+        summary.into(),
+    );
+}
+
+fn audit_entry(
+    caller: Principal,
+    user_id: Option<u64>,
+    api_key_id: Option<u64>,
+    method: &str,
+    entity: AuditEntity,
+    target_ids: Vec<u64>,
+    summary: String,
+) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let entry = AuditEntry {
+            id: log.len(),
+            timestamp: time(),
+            caller,
+            user_id,
+            api_key_id,
+            method: method.to_string(),
+            entity,
+            target_ids,
+            summary,
+        };
+        log.append(&entry).expect("cannot append to the audit log");
+    });
+}
+
+// Describes the fields that changed as `field: old -> new`, separated by semicolons.
+fn diff_summary(changes: &[(&str, String, String)]) -> String {
+    let changed: Vec<String> = changes
+        .iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| format!("{}: {:?} -> {:?}", field, old, new))
+        .collect();
+    if changed.is_empty() {
+        "no changes".to_string()
+    } else {
+        changed.join("; ")
+    }
+}
+
+// helper method to change the role of a user and record the change.
+fn do_set_role(mut user: User, role: UserRole, changed_by: Principal) -> User {
+    let previous_role = user.role.clone();
+    user.role = role.clone();
+    user.updated_at = Some(time());
+    do_insert_user(user.clone());
+
+    ROLE_CHANGES.with(|changes| {
+        let mut changes = changes.borrow_mut();
+        let id = changes.len();
+        changes.insert(
+            id,
+            RoleChange {
+                id,
+                user_id: user.id,
+                previous_role,
+                new_role: role,
+                changed_by,
+                timestamp: time(),
+            },
+        )
+    });
+
+    user
+}
+
+// helper method to perform insert for users, keeping the secondary indexes in sync.
+fn do_insert_user(user: User) {
+    if let Some(previous) = USER_STORAGE.with(|m| m.borrow().get(&user.id)) {
+        remove_user_index_entries(&previous);
+    }
+    if let Some(principal) = &user.principal {
+        PRINCIPAL_INDEX.with(|index| index.borrow_mut().insert(principal_key(principal), user.id));
+    }
+    USERNAME_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert(index_key(&user.username), user.id)
+    });
+    EMAIL_INDEX.with(|index| index.borrow_mut().insert(index_key(&user.email), user.id));
+    USER_STORAGE.with(|m| m.borrow_mut().insert(user.id, user));
+}
+
+// helper method to perform removal for users together with their index entries.
+fn do_remove_user(user_id: u64) -> Option<User> {
+    let user = USER_STORAGE.with(|m| m.borrow_mut().remove(&user_id))?;
+    remove_user_index_entries(&user);
+    TOTP_CONFIGS.with(|configs| configs.borrow_mut().remove(&user_id));
+    LOGIN_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&user_id));
+    do_revoke_api_keys(user_id);
+    Some(user)
+}
+
+// Removes the index entries of a user, leaving entries that point at another account untouched.
+fn remove_user_index_entries(user: &User) {
+    if let Some(principal) = &user.principal {
+        let key = principal_key(principal);
+        PRINCIPAL_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            if index.get(&key) == Some(user.id) {
+                index.remove(&key);
+            }
+        });
+    }
+    for (index, value) in [
+        (&USERNAME_INDEX, &user.username),
+        (&EMAIL_INDEX, &user.email),
+    ] {
+        let key = index_key(value);
+        index.with(|index| {
+            let mut index = index.borrow_mut();
+            if index.get(&key) == Some(user.id) {
+                index.remove(&key);
+            }
+        });
+    }
+}
+
+// Indexes users that are missing from the username and email indexes.
+// When legacy data holds duplicates, the account with the lowest id keeps the name.
+fn backfill_user_indexes() {
+    let users: Vec<User> =
+        USER_STORAGE.with(|storage| storage.borrow().iter().map(|(_, user)| user).collect());
+    for user in users {
+        for (index, value) in [
+            (&USERNAME_INDEX, &user.username),
+            (&EMAIL_INDEX, &user.email),
+        ] {
+            let key = index_key(value);
+            // Accounts from before the length limits can't be indexed, the insert would trap
+            if key.0.len() > StringKey::MAX_SIZE as usize {
+                ic_cdk::print(format!("User {} has a value too long to index", user.id));
+                continue;
+            }
+            index.with(|index| {
+                let mut index = index.borrow_mut();
+                match index.get(&key) {
+                    None => {
+                        index.insert(key, user.id);
+                    }
+                    Some(id) if id != user.id => ic_cdk::print(format!(
+                        "User {} shares '{}' with user {} and was not indexed",
+                        user.id, value, id
+                    )),
+                    Some(_) => {}
+                }
+            });
+        }
+    }
+}
+
+// helper method to build the case-insensitive index key of a username or email.
+fn index_key(value: &str) -> StringKey {
+    StringKey(value.to_lowercase())
+}
+
+// Rejects usernames and emails that are too long to be indexed.
+fn validate_user_fields(payload: &UserPayload) -> Result<(), Error> {
+    validate_profile_fields(&payload.username, &payload.email)?;
+    validate_password(&payload.password)
+}
+
+// helper method to validate the username and email of an account.
+fn validate_profile_fields(username: &str, email: &str) -> Result<(), Error> {
+    // Lowercasing can lengthen a string, the indexed form has to fit as well
+    if username.len() > MAX_USERNAME_LEN || index_key(username).0.len() > MAX_USERNAME_LEN {
+        return Err(Error::CustomError(format!(
+            "Username cannot be longer than {} bytes",
+            MAX_USERNAME_LEN
+        )));
+    }
+    if email.len() > MAX_EMAIL_LEN || index_key(email).0.len() > MAX_EMAIL_LEN {
+        return Err(Error::CustomError(format!(
+            "Email cannot be longer than {} bytes",
+            MAX_EMAIL_LEN
+        )));
+    }
+    if !is_valid_email(email) {
+        return Err(Error::CustomError(format!(
+            "'{}' is not a valid email address",
+            email
+        )));
+    }
+    Ok(())
+}
+
+// Checks the basic shape of an address: a local part, a single '@' and a dotted domain.
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && domain.split('.').count() >= 2
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'))
+}
+
+// Rejects a username or email already used by another account, ignoring case.
+fn ensure_unique_user(username: &str, email: &str, user_id: Option<u64>) -> Result<(), Error> {
+    let owned_by_other = |id: Option<u64>| id.is_some() && id != user_id;
+    if owned_by_other(USERNAME_INDEX.with(|index| index.borrow().get(&index_key(username)))) {
+        return Err(Error::AlreadyExists {
+            msg: format!("Username '{}' is already taken", username),
+        });
+    }
+    if owned_by_other(EMAIL_INDEX.with(|index| index.borrow().get(&index_key(email)))) {
+        return Err(Error::AlreadyExists {
+            msg: format!("Email '{}' is already registered", email),
+        });
+    }
+    Ok(())
+}
+
+// a helper method to get a user by username, ignoring case.
+fn find_user_by_username(username: &str) -> Option<User> {
+    let user_id = USERNAME_INDEX.with(|index| index.borrow().get(&index_key(username)))?;
+    USER_STORAGE.with(|storage| storage.borrow().get(&user_id))
+}
+
+// helper method to build the stable index key of a principal.
+fn principal_key(principal: &Principal) -> PrincipalKey {
+    PrincipalKey::try_from(principal.as_slice()).expect("principal is at most 29 bytes")
+}
+
+// Returns the caller's principal, rejecting anonymous calls.
+fn caller_principal() -> Result<Principal, Error> {
+    let principal = caller();
+    if principal == Principal::anonymous() {
+        return Err(Error::CustomError(
+            "Anonymous callers must sign in with an identity".to_string(),
+        ));
+    }
+    Ok(principal)
+}
+
+// a helper method to get the user bound to a principal.
+fn find_user_by_principal(principal: &Principal) -> Option<User> {
+    let user_id = PRINCIPAL_INDEX.with(|index| index.borrow().get(&principal_key(principal)))?;
+    USER_STORAGE.with(|storage| storage.borrow().get(&user_id))
+}
+
+// helper method to build the stable key of a session token.
+fn session_key(token: &str) -> SessionKey {
+    SessionKey::try_from(Sha256::digest(token.as_bytes()).as_slice()).expect("digest is 32 bytes")
+}
+
+// a helper method to get the user of a live session, dropping the session once it has expired.
+fn find_user_by_session(token: &str) -> Option<User> {
+    let key = session_key(token);
+    let session = SESSIONS.with(|sessions| sessions.borrow().get(&key))?;
+    if session.expires_at <= time() {
+        SESSIONS.with(|sessions| sessions.borrow_mut().remove(&key));
+        return None;
+    }
+    USER_STORAGE.with(|storage| storage.borrow().get(&session.user_id))
+}
+
+// Removes every session of a user together with expired sessions, returns the user's count.
+fn do_revoke_sessions(user_id: u64) -> u64 {
+    let now = time();
+    SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let stale: Vec<(SessionKey, bool)> = sessions
+            .iter()
+            .filter(|(_, session)| session.user_id == user_id || session.expires_at <= now)
+            .map(|(key, session)| (key, session.user_id == user_id))
+            .collect();
+        let mut revoked = 0;
+        for (key, owned) in stale {
+            sessions.remove(&key);
+            if owned {
+                revoked += 1;
+            }
+        }
+        revoked
+    })
+}
+
+// Checks a username/password pair and the second factor, then upgrades outdated password
+// hashes. Failed second factors count towards the lockout like wrong passwords.
+async fn authenticate_credentials(payload: &UserPayload1) -> Result<User, Error> {
+    let user = verify_credentials(&payload.username, &payload.password)?;
+    if let Err(err) = verify_second_factor(&user, payload.totp_code.clone()) {
+        record_failed_login(user.id);
+        return Err(err);
+    }
+    LOGIN_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&user.id));
+
+    if !password_needs_rehash(&user.password) {
+        return Ok(user);
+    }
+
+    let upgraded_hash = hash_password(&payload.password).await?;
+
+    // Re-read the account, it may have changed while waiting for the salt
+    match USER_STORAGE.with(|storage| storage.borrow().get(&user.id)) {
+        Some(mut current) => {
+            if current.password == user.password {
+                current.password = upgraded_hash;
+                do_insert_user(current.clone());
+            }
+            Ok(current)
+        }
+        None => Err(Error::NotFound {
+            msg: "User not found".to_string(),
+        }),
+    }
+}
+
+// Checks a username/password pair, used by password logins and to link legacy accounts.
+// Unknown usernames, wrong passwords and locked accounts fail with the same error so usernames
+// can't be probed.
+fn verify_credentials(username: &str, password: &str) -> Result<User, Error> {
+    let invalid = || Error::CustomError("Invalid username or password".to_string());
+    // No account has a longer username, and it could not be used as an index key
+    if username.len() > MAX_USERNAME_LEN {
+        return Err(invalid());
+    }
+
+    // Hash the provided password and check if it matches
+    match find_user_by_username(username) {
+        Some(user) if check_login_lockout(user.id).is_err() => {
+            // Locked accounts aren't checked, but take as long as the others
+            verify_password(password, &dummy_password_hash());
+            Err(invalid())
+        }
+        Some(user) if verify_password(password, &user.password) => Ok(user),
+        // Only existing accounts are tracked, so unknown usernames don't fill the map
+        Some(user) => {
+            record_failed_login(user.id);
+            Err(invalid())
+        }
+        None => {
+            // Hash anyway so unknown usernames take as long as wrong passwords
+            verify_password(password, &dummy_password_hash());
+            Err(invalid())
+        }
+    }
+}
+
+// A hash with the current settings that no password matches.
+fn dummy_password_hash() -> String {
+    format!(
+        "{}${}${}${}",
+        PASSWORD_HASH_SCHEME,
+        PASSWORD_HASH_ITERATIONS,
+        hex::encode([0u8; PASSWORD_SALT_LEN]),
+        hex::encode([0u8; 32])
+    )
+}
+
+// Fails while an account is locked after too many failed logins.
+fn check_login_lockout(user_id: u64) -> Result<(), Error> {
+    let locked_until = LOGIN_ATTEMPTS
+        .with(|attempts| attempts.borrow().get(&user_id))
+        .and_then(|attempts| attempts.locked_until);
+    if locked_until.is_some_and(|locked_until| locked_until > time()) {
+        return Err(Error::CustomError(
+            "Too many failed login attempts, try again later".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Counts a failed login for an existing account and locks it once the limit is reached.
+fn record_failed_login(user_id: u64) {
+    let now = time();
+    LOGIN_ATTEMPTS.with(|login_attempts| {
+        let mut login_attempts = login_attempts.borrow_mut();
+        let mut attempts = login_attempts.get(&user_id).unwrap_or_default();
+        attempts.failures += 1;
+        attempts.last_failure_at = now;
+        if attempts.failures >= MAX_FAILED_LOGINS {
+            attempts.locked_until = Some(now + lockout_duration(attempts.failures));
+        }
+        login_attempts.insert(user_id, attempts);
+    });
+}
+
+// Lockout after the given number of failures, doubling with each failure past the limit.
+fn lockout_duration(failures: u32) -> u64 {
+    let doublings = failures.saturating_sub(MAX_FAILED_LOGINS).min(32);
+    LOGIN_LOCKOUT_BASE_NANOS
+        .saturating_mul(1 << doublings)
+        .min(LOGIN_LOCKOUT_MAX_NANOS)
+}
+
+// Clears the failed logins of a username, lifting any lockout.
+#[ic_cdk::update]
+fn unlock_account(username: String, session_token: Option<String>) -> Result<(), Error> {
+    let admin = authorize(session_token, Permission::ManageUsers)?;
+    let user = find_user_by_username(&username).ok_or_else(|| Error::NotFound {
+        msg: format!("User '{}' not found", username),
+    })?;
+    match LOGIN_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&user.id)) {
+        Some(_) => {
+            audit(
+                Some(admin.id),
+                "unlock_account",
+                AuditEntity::User,
+                vec![user.id],
+                format!("lifted the lockout of '{}'", user.username),
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Account '{}' is not locked", username),
+        }),
+    }
+}
+
+// Issues an API key for partner integrations. The key may only be restricted to events the
+// caller manages, without a restriction it works for every event the caller organizes.
+#[ic_cdk::update]
+async fn create_api_key(
+    payload: ApiKeyPayload,
+    session_token: Option<String>,
+) -> Result<IssuedApiKey, Error> {
+    if payload.name.is_empty() || payload.scopes.is_empty() {
+        return Err(Error::CustomError(
+            "A name and at least one scope are required".to_string(),
+        ));
+    }
+    if payload.name.len() > MAX_API_KEY_NAME_LEN {
+        return Err(Error::CustomError(format!(
+            "Name cannot be longer than {} bytes",
+            MAX_API_KEY_NAME_LEN
+        )));
+    }
+    if payload.event_ids.len() > MAX_API_KEY_EVENTS {
+        return Err(Error::CustomError(format!(
+            "A key can be restricted to at most {} events",
+            MAX_API_KEY_EVENTS
+        )));
+    }
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= time())
+    {
+        return Err(Error::CustomError(
+            "Expiry must be in the future".to_string(),
+        ));
+    }
+
+    let user = if payload.event_ids.is_empty() {
+        authorize(session_token, Permission::CreateEvent)?
+    } else {
+        let user = authenticate_user(session_token)?;
+        for event_id in &payload.event_ids {
+            if !has_permission(&user, Permission::ManageEvent(*event_id)) {
+                return Err(Error::CustomError("Insufficient privileges".to_string()));
+            }
+        }
+        user
+    };
+
+    let secret = hex::encode(random_bytes(API_KEY_SECRET_LEN).await?);
+    let id = API_KEY_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+
+    let mut scopes = payload.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let api_key = ApiKey {
+        id,
+        name: payload.name,
+        issuer_id: user.id,
+        key_hash: hex::encode(Sha256::digest(secret.as_bytes())),
+        scopes,
+        event_ids: payload.event_ids,
+        expires_at: payload.expires_at,
+        created_at: time(),
+        last_used_at: None,
+    };
+    API_KEYS.with(|keys| keys.borrow_mut().insert(id, api_key.clone()));
+    audit(
+        Some(user.id),
+        "create_api_key",
+        AuditEntity::ApiKey,
+        vec![id],
+        format!(
+            "created '{}' with scopes {:?} for events {:?}",
+            api_key.name, api_key.scopes, api_key.event_ids
+        ),
+    );
+
+    Ok(IssuedApiKey {
+        key: format!("{}{}_{}", API_KEY_PREFIX, id, secret),
+        api_key: api_key.to_info(),
+    })
+}
+
+// Lists the API keys issued by the authenticated user.
+#[ic_cdk::query]
+fn list_api_keys(session_token: Option<String>) -> Result<Vec<ApiKeyInfo>, Error> {
+    let user = authenticate_user(session_token)?;
+    Ok(API_KEYS.with(|keys| {
+        keys.borrow()
+            .iter()
+            .filter(|(_, key)| key.issuer_id == user.id)
+            .map(|(_, key)| key.to_info())
+            .collect()
+    }))
+}
+
+// Revokes an API key, only its issuer or an admin may do so.
+#[ic_cdk::update]
+fn revoke_api_key(key_id: u64, session_token: Option<String>) -> Result<ApiKeyInfo, Error> {
+    let key = API_KEYS
+        .with(|keys| keys.borrow().get(&key_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("API key with ID {} not found.", key_id),
+        })?;
+    let user = authorize(session_token, Permission::ManageAccount(key.issuer_id))?;
+    API_KEYS.with(|keys| keys.borrow_mut().remove(&key_id));
+    audit(
+        Some(user.id),
+        "revoke_api_key",
+        AuditEntity::ApiKey,
+        vec![key_id],
+        format!("revoked '{}'", key.name),
+    );
+    Ok(key.to_info())
+}
+
+// helper method to revoke every API key issued by a user.
+fn do_revoke_api_keys(user_id: u64) {
+    API_KEYS.with(|keys| {
+        let mut keys = keys.borrow_mut();
+        let issued: Vec<u64> = keys
+            .iter()
+            .filter(|(_, key)| key.issuer_id == user_id)
+            .map(|(id, _)| id)
+            .collect();
+        for id in issued {
+            keys.remove(&id);
+        }
+    });
+}
+
+// Resolves an `esk_<id>_<secret>` API key and records its use. Query calls can't persist
+// state, so `last_used_at` only reflects update calls.
+fn authenticate_api_key(token: &str) -> Result<ApiKey, Error> {
+    let invalid = || Error::CustomError("API key is invalid or has expired".to_string());
+    let (id, secret) = token
+        .strip_prefix(API_KEY_PREFIX)
+        .and_then(|rest| rest.split_once('_'))
+        .ok_or_else(invalid)?;
+    let id: u64 = id.parse().map_err(|_| invalid())?;
+    let mut key = API_KEYS
+        .with(|keys| keys.borrow().get(&id))
+        .ok_or_else(invalid)?;
+
+    let key_hash = hex::encode(Sha256::digest(secret.as_bytes()));
+    let now = time();
+    if !constant_time_eq(key_hash.as_bytes(), key.key_hash.as_bytes())
+        || key.expires_at.is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err(invalid());
+    }
+
+    key.last_used_at = Some(now);
+    API_KEYS.with(|keys| keys.borrow_mut().insert(id, key.clone()));
+    Ok(key)
+}
+
+// API keys only grant their scopes, on their events, while the issuer still manages the event.
+fn api_key_grants(key: &ApiKey, permission: Permission) -> bool {
+    let (scope, event_id) = match permission {
+        Permission::ViewTickets(event_id) => (ApiScope::ReadTickets, event_id),
+        Permission::ViewAttendees(event_id) => (ApiScope::ReadAttendees, event_id),
+        Permission::PurchaseTickets(event_id) => (ApiScope::PurchaseTickets, event_id),
+        _ => return false,
+    };
+    key.scopes.contains(&scope)
+        && (key.event_ids.is_empty() || key.event_ids.contains(&event_id))
+        && load_user(key.issuer_id)
+            .is_ok_and(|issuer| has_permission(&issuer, Permission::ManageEvent(event_id)))
+}
+
+// Like `authorize`, but also accepts an API key in place of the session token.
+fn authorize_caller(
+    session_token: Option<String>,
+    permission: Permission,
+) -> Result<Caller, Error> {
+    match session_token {
+        Some(token) if token.starts_with(API_KEY_PREFIX) => {
+            let key = authenticate_api_key(&token)?;
+            if !api_key_grants(&key, permission) {
+                return Err(Error::CustomError("Insufficient privileges".to_string()));
+            }
+            Ok(Caller::ApiKey(key))
+        }
+        session_token => authorize(session_token, permission).map(Caller::User),
+    }
+}
+
+// Authenticates the caller and checks that it holds the permission an endpoint declares.
+fn authorize(session_token: Option<String>, permission: Permission) -> Result<User, Error> {
+    let user = authenticate_user(session_token)?;
+
+    if has_permission(&user, permission) {
+        Ok(user) // Authorization successful
+    } else {
+        Err(Error::CustomError("Insufficient privileges".to_string()))
+    }
+}
+
+// Admins hold every permission. Event scoped permissions come from organizing the event
+// or from a per-event role granted by its organizers.
+fn has_permission(user: &User, permission: Permission) -> bool {
+    if user.role == UserRole::Admin {
+        return true;
+    }
+
+    let organizes = |event_id: u64, include_co_organizers: bool| {
+        _get_event(&event_id).is_some_and(|event| {
+            event.organizer_id == user.id
+                || (include_co_organizers && event.co_organizer_ids.contains(&user.id))
+        })
+    };
+
+    match permission {
+        Permission::ManageAccount(user_id) => user.id == user_id,
+        Permission::ManageRoles
+        | Permission::ManageUsers
+        | Permission::ManageOutbox
+        | Permission::ViewAuditLog
+        | Permission::ManageCategories => false,
+        Permission::CreateEvent => user.role == UserRole::Organizer,
+        Permission::ManageOrganizers(event_id) => organizes(event_id, false),
+        Permission::ManageEvent(event_id)
+        | Permission::ManageStaff(event_id)
+        | Permission::ManageTickets(event_id)
+        | Permission::ViewTickets(event_id)
+        | Permission::ViewAttendees(event_id) => organizes(event_id, true),
+        Permission::CheckInTickets(event_id) => {
+            organizes(event_id, true)
+                || get_event_role(event_id, user.id) == Some(EventRole::Scanner)
+        }
+        Permission::PurchaseTickets(_) => true,
+        Permission::ManageVenue(venue_id) => VENUES
+            .with(|service| service.borrow().get(&venue_id))
+            .is_some_and(|venue| venue.created_by == user.id),
+    }
+}
+
+// a helper method to get the per-event role of a user.
+fn get_event_role(event_id: u64, user_id: u64) -> Option<EventRole> {
+    EVENT_STAFF
+        .with(|staff| staff.borrow().get(&(event_id, user_id)))
+        .map(|staff| staff.role)
+}
+
+// Resolves the caller to a user, either through a session token or the calling principal.
+fn authenticate_user(session_token: Option<String>) -> Result<User, Error> {
+    if let Some(token) = session_token {
+        if token.starts_with(API_KEY_PREFIX) {
+            return Err(Error::CustomError(
+                "API keys are not accepted by this endpoint".to_string(),
+            ));
+        }
+        return find_user_by_session(&token)
+            .ok_or_else(|| Error::CustomError("Session is invalid or has expired".to_string()));
+    }
+
+    // Resolve the calling principal to its user
+    let principal = caller_principal()?;
+    match find_user_by_principal(&principal) {
+        // The principal alone is not a second factor
+        Some(user) if totp_enabled(user.id) => Err(Error::CustomError(
+            "Two-factor authentication is enabled, sign in with login_principal and pass the session token"
+                .to_string(),
+        )),
+        Some(user) => Ok(user), // Authentication successful
+        None => Err(Error::NotFound {
+            msg: format!("No user is linked to principal {}", principal),
+        }), // User not found
+    }
+}
+
+// Event Queries
+#[ic_cdk::update]
+fn add_event(event: EventPayload, session_token: Option<String>) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::CreateEvent)?;
+
+    // Validate event payload: all fields are required
+    if event.event_name.is_empty()
+        || event.details.is_empty()
+        || (event.location.is_empty() && event.venue_id.is_none())
+        || event.start_date.is_empty()
+        || event.end_date.is_empty()
+    {
+        return Err(Error::CustomError("All fields are required".to_string()));
+    }
+    validate_event_text(&event.event_name, &event.details, &event.location)?;
+    let (start_date, end_date, timezone) = parse_event_dates(&event)?;
+    let (category_id, tags) = parse_event_taxonomy(event.category_id, event.tags.as_deref())?;
+    let (venue_id, location) = parse_event_venue(event.venue_id, &event.location)?;
+
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+
+    let timestamp = time(); // Get the current timestamp
+
+    let event = Event {
+        id,
+        event_name: event.event_name,
+        details: event.details,
+        location,
+        start_date,
+        end_date,
+        timezone,
+        timestamp,
+        organizer_id: user.id,
+        co_organizer_ids: Vec::new(),
+        status: EventStatus::Draft,
+        series_id: None,
+        category_id,
+        tags: Some(tags),
+        venue_id,
+    };
+    do_insert_event(&event);
+    audit(
+        Some(user.id),
+        "add_event",
+        AuditEntity::Event,
+        vec![event.id],
+        format!("created '{}'", event.event_name),
+    );
+    Ok(event)
+}
+
+#[ic_cdk::update]
+fn update_event(
+    event_id: u64,
+    payload: EventPayload,
+    session_token: Option<String>,
+) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::ManageEvent(event_id))?;
+
+    // Validate that all fields in the payload are filled
+    if payload.event_name.is_empty()
+        || payload.details.is_empty()
+        || (payload.location.is_empty() && payload.venue_id.is_none())
+        || payload.start_date.is_empty()
+        || payload.end_date.is_empty()
+    {
+        return Err(Error::CustomError(
+            "All fields in the payload are required".to_string(),
+        ));
+    }
+    validate_event_text(&payload.event_name, &payload.details, &payload.location)?;
+
+    match EVENTS_STORAGE.with(|service| service.borrow().get(&event_id)) {
+        Some(mut event) => {
+            if matches!(
+                event.status,
+                EventStatus::Cancelled | EventStatus::Completed
+            ) {
+                return Err(Error::CustomError(format!(
+                    "A {:?} event cannot be changed",
+                    event.status
+                )));
+            }
+            let (start_date, end_date, timezone) = parse_event_dates(&payload)?;
+            let (category_id, tags) =
+                parse_event_taxonomy(payload.category_id, payload.tags.as_deref())?;
+            let (venue_id, location) = parse_event_venue(payload.venue_id, &payload.location)?;
+            if let Some(venue_id) = venue_id.filter(|venue_id| event.venue_id != Some(*venue_id)) {
+                check_venue_capacity(venue_id, event_id, 0)?;
+            }
+
+            let summary = diff_summary(&[
+                (
+                    "event_name",
+                    event.event_name.clone(),
+                    payload.event_name.clone(),
+                ),
+                ("details", event.details.clone(), payload.details.clone()),
+                ("location", event.location.clone(), location.clone()),
+                (
+                    "start_date",
+                    format_instant(event.start_date, &event.timezone),
+                    format_instant(start_date, &timezone),
+                ),
+                (
+                    "end_date",
+                    format_instant(event.end_date, &event.timezone),
+                    format_instant(end_date, &timezone),
+                ),
+                ("timezone", event.timezone.clone(), timezone.clone()),
+                (
+                    "category_id",
+                    format!("{:?}", event.category_id),
+                    format!("{:?}", category_id),
+                ),
+                ("tags", event_tags(&event).join(", "), tags.join(", ")),
+                (
+                    "venue_id",
+                    format!("{:?}", event.venue_id),
+                    format!("{:?}", venue_id),
+                ),
+            ]);
+
+            event.event_name = payload.event_name;
+            event.details = payload.details;
+            event.location = location;
+            event.start_date = start_date;
+            event.end_date = end_date;
+            event.timezone = timezone;
+            event.category_id = category_id;
+            event.tags = Some(tags);
+            event.venue_id = venue_id;
+
+            do_insert_event(&event);
+            audit(
+                Some(user.id),
+                "update_event",
+                AuditEntity::Event,
+                vec![event_id],
+                summary,
+            );
+            Ok(event)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't update an event with id={}. event not found",
+                event_id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+fn delete_event(event_id: Option<u64>, session_token: Option<String>) -> Result<Event, Error> {
+    // Validate that the event_id is provided
+    let event_id = match event_id {
+        Some(id) => id,
+        None => return Err(Error::CustomError("Event ID must be provided".to_string())),
+    };
+
+    // Authenticate the user
+    let user = authorize(session_token, Permission::ManageEvent(event_id))?;
+
+    // Sold tickets are records of payments, events that sold any have to be cancelled instead
+    let tickets = get_event_tickets(event_id);
+    if tickets.iter().any(|ticket| {
+        !matches!(
+            ticket.status,
+            TicketStatus::Available | TicketStatus::Voided
+        )
+    }) {
+        return Err(Error::CustomError(format!(
+            "Event with id={} has sold tickets, cancel it instead",
+            event_id
+        )));
+    }
+
+    // Proceed with the deletion, taking the unsold tickets and staff roles with it
+    match EVENTS_STORAGE.with(|service| service.borrow_mut().remove(&event_id)) {
+        Some(event) => {
+            update_search_index(event_id, Some(&event), None);
+            update_taxonomy_indexes(event_id, Some(&event), None);
+            update_venue_index(event_id, Some(&event), None);
+            update_date_index(event_id, Some(&event), None);
+            for ticket in &tickets {
+                do_remove_ticket(ticket);
+            }
+            EVENT_STAFF.with(|staff| {
+                let mut staff = staff.borrow_mut();
+                let keys: Vec<(u64, u64)> = staff
+                    .range((event_id, 0)..=(event_id, u64::MAX))
+                    .map(|(key, _)| key)
+                    .collect();
+                for key in keys {
+                    staff.remove(&key);
+                }
+            });
+            EVENT_ATTENDEES.with(|service| {
+                let mut service = service.borrow_mut();
+                let keys: Vec<(u64, u64)> = service
+                    .range((event_id, 0)..=(event_id, u64::MAX))
+                    .map(|(key, _)| key)
+                    .collect();
+                for key in keys {
+                    service.remove(&key);
+                }
+            });
+            audit(
+                Some(user.id),
+                "delete_event",
+                AuditEntity::Event,
+                vec![event_id],
+                format!(
+                    "deleted '{}' and {} unsold tickets",
+                    event.event_name,
+                    tickets.len()
+                ),
+            );
+            Ok(event)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't delete an event with id={}. event not found.",
+                event_id
+            ),
+        }),
+    }
+}
+
+// Creates a recurring series, expanding it into draft occurrences that each have their own
+// tickets and attendees.
+#[ic_cdk::update]
+fn add_event_series(
+    payload: EventSeriesPayload,
+    session_token: Option<String>,
+) -> Result<EventSeriesDetails, Error> {
+    let user = authorize(session_token, Permission::CreateEvent)?;
+
+    let event = &payload.event;
+    if event.event_name.is_empty()
+        || event.details.is_empty()
+        || (event.location.is_empty() && event.venue_id.is_none())
+        || event.start_date.is_empty()
+        || event.end_date.is_empty()
+    {
+        return Err(Error::CustomError("All fields are required".to_string()));
+    }
+    validate_event_text(&event.event_name, &event.details, &event.location)?;
+    let (start_date, end_date, timezone) = parse_event_dates(event)?;
+    let (category_id, tags) = parse_event_taxonomy(event.category_id, event.tags.as_deref())?;
+    let (venue_id, location) = parse_event_venue(event.venue_id, &event.location)?;
+    let rule = parse_recurrence(&payload.recurrence, &timezone)?;
+    let dates = expand_recurrence(&rule, start_date, end_date, &timezone)?;
+
+    let series_id = SERIES_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+
+    let timestamp = time();
+    let mut occurrences = Vec::new();
+    for (start_date, end_date) in dates {
+        let id = ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment id counter");
+        let occurrence = Event {
+            id,
+            event_name: event.event_name.clone(),
+            details: event.details.clone(),
+            location: location.clone(),
+            start_date,
+            end_date,
+            timezone: timezone.clone(),
+            timestamp,
+            organizer_id: user.id,
+            co_organizer_ids: Vec::new(),
+            status: EventStatus::Draft,
+            series_id: Some(series_id),
+            category_id,
+            tags: Some(tags.clone()),
+            venue_id,
+        };
+        do_insert_event(&occurrence);
+        occurrences.push(occurrence);
+    }
+
+    let series = EventSeries {
+        id: series_id,
+        organizer_id: user.id,
+        rule,
+        occurrence_ids: occurrences.iter().map(|event| event.id).collect(),
+        created_at: timestamp,
+    };
+    EVENT_SERIES.with(|service| service.borrow_mut().insert(series_id, series.clone()));
+    audit(
+        Some(user.id),
+        "add_event_series",
+        AuditEntity::Event,
+        series.occurrence_ids.clone(),
+        format!(
+            "created series {} of '{}' with {} occurrences",
+            series_id,
+            event.event_name,
+            occurrences.len()
+        ),
+    );
+    Ok(EventSeriesDetails {
+        series,
+        occurrences,
+    })
+}
+
+#[ic_cdk::query]
+fn get_event_series(series_id: u64) -> Result<EventSeriesDetails, Error> {
+    let series = load_series(series_id)?;
+    // Drafts are only visible to their organizers
+    let occurrences = series
+        .occurrence_ids
+        .iter()
+        .filter_map(_get_event)
+        .filter(|event| event.status != EventStatus::Draft)
+        .collect();
+    Ok(EventSeriesDetails {
+        series,
+        occurrences,
+    })
+}
+
+// Edits every occurrence of a series that hasn't been cancelled or completed. A single
+// occurrence, including its dates, is edited with `update_event`.
+#[ic_cdk::update]
+fn update_event_series(
+    series_id: u64,
+    payload: EventSeriesUpdatePayload,
+    session_token: Option<String>,
+) -> Result<EventSeriesDetails, Error> {
+    let user = authenticate_user(session_token)?;
+    if payload.event_name.is_empty()
+        || payload.details.is_empty()
+        || (payload.location.is_empty() && payload.venue_id.is_none())
+    {
+        return Err(Error::CustomError(
+            "All fields in the payload are required".to_string(),
+        ));
+    }
+    validate_event_text(&payload.event_name, &payload.details, &payload.location)?;
+    let (category_id, tags) = parse_event_taxonomy(payload.category_id, payload.tags.as_deref())?;
+    let (venue_id, location) = parse_event_venue(payload.venue_id, &payload.location)?;
+
+    let series = load_series(series_id)?;
+    let mut occurrences: Vec<Event> = series
+        .occurrence_ids
+        .iter()
+        .filter_map(_get_event)
+        .collect();
+    if occurrences
+        .iter()
+        .any(|event| !has_permission(&user, Permission::ManageEvent(event.id)))
+    {
+        return Err(Error::CustomError("Insufficient privileges".to_string()));
+    }
+    let editable = |event: &Event| {
+        !matches!(
+            event.status,
+            EventStatus::Cancelled | EventStatus::Completed
+        )
+    };
+    // Every occurrence moving to the venue has to fit, before any of them is changed
+    if let Some(venue_id) = venue_id {
+        for event in occurrences
+            .iter()
+            .filter(|event| editable(event) && event.venue_id != Some(venue_id))
+        {
+            check_venue_capacity(venue_id, event.id, 0)?;
+        }
+    }
+
+    let mut updated = Vec::new();
+    for event in occurrences.iter_mut().filter(|event| editable(event)) {
+        event.event_name = payload.event_name.clone();
+        event.details = payload.details.clone();
+        event.location = location.clone();
+        event.category_id = category_id;
+        event.tags = Some(tags.clone());
+        event.venue_id = venue_id;
+        do_insert_event(event);
+        updated.push(event.id);
+    }
+
+    audit(
+        Some(user.id),
+        "update_event_series",
+        AuditEntity::Event,
+        updated.clone(),
+        format!(
+            "series {}: event_name: {:?}, details: {:?}, location: {:?}, category_id: {:?}, \
+             tags: {:?}, venue_id: {:?} on {} occurrences",
+            series_id,
+            payload.event_name,
+            payload.details,
+            location,
+            category_id,
+            tags.join(", "),
+            venue_id,
+            updated.len()
+        ),
+    );
+    Ok(EventSeriesDetails {
+        series,
+        occurrences,
+    })
+}
+
+// a helper method to get a series by id.
+fn load_series(series_id: u64) -> Result<EventSeries, Error> {
+    EVENT_SERIES
+        .with(|service| service.borrow().get(&series_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Event series with id={} not found", series_id),
+        })
+}
+
+// Validates a recurrence payload, the series has to end by a count or an until date.
+fn parse_recurrence(payload: &RecurrencePayload, timezone: &str) -> Result<RecurrenceRule, Error> {
+    if payload.interval == 0 {
+        return Err(Error::CustomError(
+            "Interval must be at least 1".to_string(),
+        ));
+    }
+    if payload.count.is_none() && payload.until.is_none() {
+        return Err(Error::CustomError(
+            "A series needs a count or an until date".to_string(),
+        ));
+    }
+    if payload.count.is_some_and(|count| count == 0) {
+        return Err(Error::CustomError("Count must be at least 1".to_string()));
+    }
+    if payload.exceptions.len() > MAX_SERIES_OCCURRENCES {
+        return Err(Error::CustomError(format!(
+            "A series can have at most {} exceptions",
+            MAX_SERIES_OCCURRENCES
+        )));
+    }
+
+    let tz: Tz = timezone.parse().expect("timezone was validated");
+    let until = payload
+        .until
+        .as_deref()
+        .map(|until| parse_event_instant(until, tz, true))
+        .transpose()?;
+    let exceptions = payload
+        .exceptions
+        .iter()
+        .map(|exception| {
+            parse_date(exception.trim())
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .ok_or_else(|| Error::CustomError(format!("'{}' is not a valid date", exception)))
+        })
+        .collect::<Result<Vec<String>, Error>>()?;
+
+    Ok(RecurrenceRule {
+        frequency: payload.frequency,
+        interval: payload.interval,
+        count: payload.count,
+        until,
+        exceptions,
+    })
+}
+
+// Expands a rule into the start and end of every occurrence. Occurrences repeat at the same
+// local time, so they follow daylight saving changes. Exceptions are skipped after applying
+// the count, as in RFC 5545.
+fn expand_recurrence(
+    rule: &RecurrenceRule,
+    start_date: u64,
+    end_date: u64,
+    timezone: &str,
+) -> Result<Vec<(u64, u64)>, Error> {
+    let tz: Tz = timezone.parse().expect("timezone was validated");
+    let duration = end_date - start_date;
+    let first = Utc
+        .timestamp_nanos(start_date as i64)
+        .with_timezone(&tz)
+        .naive_local();
+
+    let mut dates = Vec::new();
+    for index in 0u32.. {
+        if rule.count.is_some_and(|count| index >= count) {
+            break;
+        }
+        let Some(local) = index
+            .checked_mul(rule.interval)
+            .and_then(|steps| match rule.frequency {
+                Frequency::Daily => first.checked_add_days(Days::new(steps as u64)),
+                Frequency::Weekly => first.checked_add_days(Days::new(steps as u64 * 7)),
+                Frequency::Monthly => first.checked_add_months(Months::new(steps)),
+            })
+        else {
+            break;
+        };
+        // Local times skipped by a daylight saving change move to the next valid instant
+        let Some(start) = tz
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                tz.from_local_datetime(&(local + chrono::Duration::hours(1)))
+                    .earliest()
+            })
+            .and_then(|start| start.timestamp_nanos_opt())
+            .and_then(|nanos| u64::try_from(nanos).ok())
+        else {
+            break;
+        };
+        if rule.until.is_some_and(|until| start >= until) {
+            break;
+        }
+
+        if !rule
+            .exceptions
+            .contains(&local.format("%Y-%m-%d").to_string())
+        {
+            if dates.len() == MAX_SERIES_OCCURRENCES {
+                return Err(Error::CustomError(format!(
+                    "A series can have at most {} occurrences",
+                    MAX_SERIES_OCCURRENCES
+                )));
+            }
+            dates.push((start, start + duration));
+        }
+    }
+
+    if dates.is_empty() {
+        return Err(Error::CustomError(
+            "The recurrence rule produces no occurrences".to_string(),
+        ));
+    }
+    Ok(dates)
+}
+
+// Makes a draft or postponed event public and open for ticket sales.
+#[ic_cdk::update]
+fn publish_event(event_id: u64, session_token: Option<String>) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::ManageEvent(event_id))?;
+    let event = load_event(event_id)?;
+    if event.end_date <= time() {
+        return Err(Error::CustomError(
+            "An event that has already ended cannot be published".to_string(),
+        ));
+    }
+    do_set_event_status(
+        event,
+        &[EventStatus::Draft, EventStatus::Postponed],
+        EventStatus::Published,
+        Some(user.id),
+        "publish_event",
+    )
+}
+
+// Cancels an event: unsold tickets are voided, sold tickets become refundable and every
+// ticket holder is notified by email.
+#[ic_cdk::update]
+fn cancel_event(event_id: u64, session_token: Option<String>) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::ManageEvent(event_id))?;
+    let event = do_set_event_status(
+        load_event(event_id)?,
+        &[
+            EventStatus::Draft,
+            EventStatus::Published,
+            EventStatus::Postponed,
+        ],
+        EventStatus::Cancelled,
+        Some(user.id),
+        "cancel_event",
+    )?;
+
+    let mut voided = Vec::new();
+    let mut refundable = Vec::new();
+    let mut holder_ids = Vec::new();
+    for mut ticket in get_event_tickets(event_id) {
+        match ticket.status {
+            TicketStatus::Available => {
+                ticket.status = TicketStatus::Voided;
+                voided.push(ticket.ticket_id);
+            }
+            TicketStatus::Sold | TicketStatus::CheckedIn => {
+                ticket.status = TicketStatus::Refundable;
+                refundable.push(ticket.ticket_id);
+                if let Some(holder_id) = ticket.holder_id {
+                    if !holder_ids.contains(&holder_id) {
+                        holder_ids.push(holder_id);
+                    }
+                }
+            }
+            TicketStatus::Voided | TicketStatus::Refundable => continue,
+        }
+        do_insert_ticket(&ticket);
+    }
+
+    // Attendees without an account, e.g. sold through partners, are notified by the partner
+    for holder in holder_ids.iter().filter_map(|id| load_user(*id).ok()) {
+        send_email(
+            &holder.email,
+            &format!("{} has been cancelled", event.event_name),
+            &format!(
+                "Hi {}, unfortunately {} has been cancelled. Your tickets are marked for a refund.",
+                holder.username, event.event_name
+            ),
+        );
+    }
+
+    audit(
+        Some(user.id),
+        "cancel_event",
+        AuditEntity::Ticket,
+        voided.iter().chain(&refundable).copied().collect(),
+        format!(
+            "voided {} unsold and flagged {} sold tickets of event {} as refundable, notified {} holders",
+            voided.len(),
+            refundable.len(),
+            event_id,
+            holder_ids.len()
+        ),
+    );
+    Ok(event)
+}
+
+// Puts a published event on hold, ticket sales stop until it is published again.
+#[ic_cdk::update]
+fn postpone_event(event_id: u64, session_token: Option<String>) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::ManageEvent(event_id))?;
+    do_set_event_status(
+        load_event(event_id)?,
+        &[EventStatus::Published],
+        EventStatus::Postponed,
+        Some(user.id),
+        "postpone_event",
+    )
+}
+
+// helper method to move an event to a new status, only from the allowed statuses.
+fn do_set_event_status(
+    mut event: Event,
+    allowed_from: &[EventStatus],
+    status: EventStatus,
+    user_id: Option<u64>,
+    method: &str,
+) -> Result<Event, Error> {
+    if !allowed_from.contains(&event.status) {
+        return Err(Error::CustomError(format!(
+            "A {:?} event cannot become {:?}",
+            event.status, status
+        )));
+    }
+
+    let summary = format!("status: {:?} -> {:?}", event.status, status);
+    event.status = status;
+    do_insert_event(&event);
+    match user_id {
+        Some(user_id) => audit(
+            Some(user_id),
+            method,
+            AuditEntity::Event,
+            vec![event.id],
+            summary,
+        ),
+        None => audit_system(method, AuditEntity::Event, vec![event.id], summary),
+    }
+    Ok(event)
+}
+
+// Timer callback marking published events completed once they have ended.
+fn complete_ended_events() {
+    let now = time();
+    let ended: Vec<Event> = EVENTS_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.status == EventStatus::Published && event.end_date <= now)
+            .collect()
+    });
+    for event in ended {
+        let _ = do_set_event_status(
+            event,
+            &[EventStatus::Published],
+            EventStatus::Completed,
+            None,
+            "complete_ended_events",
+        );
+    }
+}
+
+// Invites a user to co-organize an event, only the owning organizer or an admin may do so.
+#[ic_cdk::update]
+fn add_co_organizer(
+    event_id: u64,
+    user_id: u64,
+    session_token: Option<String>,
+) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::ManageOrganizers(event_id))?;
+    let mut event = load_event(event_id)?;
+    load_user(user_id)?;
+
+    if event.organizer_id == user_id || event.co_organizer_ids.contains(&user_id) {
+        return Err(Error::AlreadyExists {
+            msg: format!(
+                "User with ID {} already organizes event {}",
+                user_id, event_id
+            ),
+        });
+    }
+
+    if event.co_organizer_ids.len() >= MAX_CO_ORGANIZERS {
+        return Err(Error::CustomError(format!(
+            "An event cannot have more than {} co-organizers",
+            MAX_CO_ORGANIZERS
+        )));
+    }
+
+    event.co_organizer_ids.push(user_id);
+    do_insert_event(&event);
+    audit(
+        Some(user.id),
+        "add_co_organizer",
+        AuditEntity::Event,
+        vec![event_id, user_id],
+        format!("added co-organizer {}", user_id),
+    );
+    Ok(event)
+}
+
+#[ic_cdk::update]
+fn remove_co_organizer(
+    event_id: u64,
+    user_id: u64,
+    session_token: Option<String>,
+) -> Result<Event, Error> {
+    let user = authorize(session_token, Permission::ManageOrganizers(event_id))?;
+    let mut event = load_event(event_id)?;
+
+    if !event.co_organizer_ids.contains(&user_id) {
+        return Err(Error::NotFound {
+            msg: format!(
+                "User with ID {} is not a co-organizer of event {}",
+                user_id, event_id
+            ),
+        });
+    }
+
+    event.co_organizer_ids.retain(|id| *id != user_id);
+    do_insert_event(&event);
+    audit(
+        Some(user.id),
+        "remove_co_organizer",
+        AuditEntity::Event,
+        vec![event_id, user_id],
+        format!("removed co-organizer {}", user_id),
+    );
+    Ok(event)
+}
+
+// Grants a per-event role, e.g. a scanner for the door staff of one event.
+#[ic_cdk::update]
+fn grant_event_role(
+    payload: EventStaffPayload,
+    session_token: Option<String>,
+) -> Result<EventStaff, Error> {
+    let user = authorize(session_token, Permission::ManageStaff(payload.event_id))?;
+    load_event(payload.event_id)?;
+    load_user(payload.user_id)?;
+
+    let staff = EventStaff {
+        event_id: payload.event_id,
+        user_id: payload.user_id,
+        role: payload.role,
+        granted_by: user.id,
+        granted_at: time(),
+    };
+    EVENT_STAFF.with(|event_staff| {
+        event_staff
+            .borrow_mut()
+            .insert((staff.event_id, staff.user_id), staff.clone())
+    });
+    audit(
+        Some(user.id),
+        "grant_event_role",
+        AuditEntity::Event,
+        vec![staff.event_id, staff.user_id],
+        format!("granted {:?} to user {}", staff.role, staff.user_id),
+    );
+    Ok(staff)
+}
+
+#[ic_cdk::update]
+fn revoke_event_role(
+    event_id: u64,
+    user_id: u64,
+    session_token: Option<String>,
+) -> Result<EventStaff, Error> {
+    let user = authorize(session_token, Permission::ManageStaff(event_id))?;
+    match EVENT_STAFF.with(|staff| staff.borrow_mut().remove(&(event_id, user_id))) {
+        Some(staff) => {
+            audit(
+                Some(user.id),
+                "revoke_event_role",
+                AuditEntity::Event,
+                vec![event_id, user_id],
+                format!("revoked {:?} from user {}", staff.role, user_id),
+            );
+            Ok(staff)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "User with ID {} has no role for event {}",
+                user_id, event_id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_event_staff(event_id: u64, session_token: Option<String>) -> Result<Vec<EventStaff>, Error> {
+    authorize(session_token, Permission::ManageStaff(event_id))?;
+    Ok(EVENT_STAFF.with(|staff| {
+        staff
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|(_, staff)| staff)
+            .collect()
+    }))
+}
+
+// Drafts are only returned to their organizers, to everyone else they don't exist.
+#[ic_cdk::query]
+fn get_event(event_id: u64, session_token: Option<String>) -> Result<Event, Error> {
+    let event = load_event(event_id)?;
+    if event.status == EventStatus::Draft
+        && authorize(session_token, Permission::ManageEvent(event_id)).is_err()
+    {
+        return Err(Error::NotFound {
+            msg: format!("an event with id={} not found", event_id),
+        });
+    }
+    Ok(event)
+}
+
+// a helper method to get an event by id, drafts included.
+fn load_event(event_id: u64) -> Result<Event, Error> {
+    match _get_event(&event_id) {
+        Some(event) => Ok(event),
+        None => Err(Error::NotFound {
+            msg: format!("an event with id={} not found", event_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_event_by_name(event_name: String) -> Result<Option<Event>, Error> {
+    // Validate that the event username is not empty
+    if event_name.is_empty() {
+        return Err(Error::CustomError(
+            "Event name cannot be empty".to_string(),
+        ));
+    }
+
+    let lowercase_name = event_name.to_lowercase();
     let mut found_event = None;
 
-    EVENTS_STORAGE.with(|service| {
-        let borrowed_service = service.borrow();
-        for (_, event) in borrowed_service.iter() {
-            if event.event_name.to_lowercase() == lowercase_name {
-                found_event = Some(event.clone());
+    EVENTS_STORAGE.with(|service| {
+        let borrowed_service = service.borrow();
+        for (_, event) in borrowed_service.iter() {
+            if event.status != EventStatus::Draft
+                && event.event_name.to_lowercase() == lowercase_name
+            {
+                found_event = Some(event.clone());
+                break;
+            }
+        }
+    });
+
+    // Check if an event with the provided username was found
+    if let Some(event) = found_event {
+        Ok(Some(event))
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Event with name '{}' not found", event_name),
+        })
+    }
+}
+
+#[ic_cdk::query]
+fn get_events() -> Result<Vec<Event>, Error> {
+    let events = EVENTS_STORAGE.with(|service| {
+        let borrowed_service = service.borrow();
+        // Drafts are only visible to their organizers
+        let events: Vec<Event> = borrowed_service
+            .iter()
+            .map(|(_, event)| event.clone())
+            .filter(|event| event.status != EventStatus::Draft)
+            .collect();
+
+        events
+    });
+
+    if events.is_empty() {
+        Err(Error::CustomError("No events available".to_string()))
+    } else {
+        Ok(events)
+    }
+}
+
+// Events that haven't started yet, soonest first.
+#[ic_cdk::query]
+fn get_upcoming_events() -> Result<Vec<Event>, Error> {
+    let current_time = time();
+    Ok(get_events_sorted(|event| event.start_date > current_time))
+}
+
+// Events that have started but not ended yet.
+#[ic_cdk::query]
+fn get_ongoing_events() -> Result<Vec<Event>, Error> {
+    let current_time = time();
+    Ok(get_events_sorted(|event| {
+        event.start_date <= current_time && current_time < event.end_date
+    }))
+}
+
+// Events that have ended, in the order they took place.
+#[ic_cdk::query]
+fn get_past_events() -> Result<Vec<Event>, Error> {
+    let current_time = time();
+    Ok(get_events_sorted(|event| event.end_date <= current_time))
+}
+
+// One page of the listed events matching a filter, in the requested order.
+#[ic_cdk::query]
+fn list_events(
+    filter: EventFilter,
+    sort: EventSort,
+    cursor: Option<EventCursor>,
+    limit: u32,
+) -> Result<EventPage, Error> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    if cursor.as_ref().is_some_and(|cursor| {
+        matches!(cursor.key, EventSortKey::Name(_)) != matches!(sort, EventSort::Name)
+    }) {
+        return Err(Error::CustomError(
+            "The cursor belongs to a different sort".to_string(),
+        ));
+    }
+
+    let location = filter
+        .location
+        .as_ref()
+        .map(|location| location.to_lowercase());
+    let tag = filter.tag.as_deref().map(normalize_tag).transpose()?;
+    let matches = |event: &Event| {
+        event.status != EventStatus::Draft
+            && filter.status.is_none_or(|status| event.status == status)
+            && filter.from.is_none_or(|from| event.end_date > from)
+            && filter.to.is_none_or(|to| event.start_date < to)
+            && filter.organizer_id.is_none_or(|organizer_id| {
+                event.organizer_id == organizer_id || event.co_organizer_ids.contains(&organizer_id)
+            })
+            && location
+                .as_ref()
+                .is_none_or(|location| event.location.to_lowercase().contains(location.as_str()))
+            && filter
+                .category_id
+                .is_none_or(|category_id| event.category_id == Some(category_id))
+            && tag
+                .as_ref()
+                .is_none_or(|tag| event_tags(event).contains(tag))
+            && filter
+                .venue_id
+                .is_none_or(|venue_id| event.venue_id == Some(venue_id))
+    };
+
+    // Without a narrower index, date sorted pages walk the start date index
+    if matches!(sort, EventSort::StartDate | EventSort::StartDateDesc)
+        && filter.venue_id.is_none()
+        && filter.category_id.is_none()
+        && tag.is_none()
+    {
+        return Ok(list_events_by_date(
+            sort,
+            cursor.as_ref(),
+            limit,
+            filter.to,
+            matches,
+        ));
+    }
+
+    let mut events: Vec<Event> = filter_candidates(&filter, tag.as_deref())
+        .into_iter()
+        .filter(|event| matches(event))
+        .filter(|event| {
+            cursor.as_ref().is_none_or(|cursor| {
+                compare_cursors(sort, &event_cursor(sort, event), cursor).is_gt()
+            })
+        })
+        .collect();
+    events.sort_by_cached_key(|event| event_cursor(sort, event));
+    if matches!(sort, EventSort::StartDateDesc | EventSort::Newest) {
+        events.reverse();
+    }
+
+    let next_cursor = if events.len() > limit {
+        events.truncate(limit);
+        events.last().map(|event| event_cursor(sort, event))
+    } else {
+        None
+    };
+    Ok(EventPage {
+        events,
+        next_cursor,
+    })
+}
+
+// a helper method to page through events in start date order, loading one event at a time.
+// At most `MAX_EVENT_SCAN` events are looked at, a page that is cut short still gets a cursor
+// to continue from. Ascending pages stop at the first event starting at or after `to`.
+fn list_events_by_date(
+    sort: EventSort,
+    cursor: Option<&EventCursor>,
+    limit: usize,
+    to: Option<u64>,
+    matches: impl Fn(&Event) -> bool,
+) -> EventPage {
+    let start = cursor.map(|cursor| match cursor.key {
+        EventSortKey::Time(start_date) => (start_date, cursor.id),
+        EventSortKey::Name(_) => unreachable!("name cursors are rejected"),
+    });
+    let keys: Box<dyn Iterator<Item = (u64, u64)>> = match sort {
+        EventSort::StartDateDesc => {
+            // The stable map only iterates forwards, step back one key at a time
+            let before = |key: &(u64, u64)| {
+                EVENT_DATE_INDEX.with(|index| {
+                    index
+                        .borrow()
+                        .iter_upper_bound(key)
+                        .next()
+                        .map(|(key, _)| key)
+                })
+            };
+            let first = before(&start.unwrap_or((u64::MAX, u64::MAX)));
+            Box::new(std::iter::successors(first, before))
+        }
+        _ => {
+            let keys: Vec<(u64, u64)> = EVENT_DATE_INDEX.with(|index| {
+                index
+                    .borrow()
+                    .range(start.unwrap_or((0, 0))..)
+                    .map(|(key, _)| key)
+                    .filter(|key| Some(*key) != start)
+                    .take_while(|(start_date, _)| to.is_none_or(|to| *start_date < to))
+                    .take(MAX_EVENT_SCAN)
+                    .collect()
+            });
+            Box::new(keys.into_iter())
+        }
+    };
+
+    let mut events = Vec::new();
+    let mut scanned = 0;
+    let mut last_scanned = None;
+    for key in keys.take(MAX_EVENT_SCAN) {
+        scanned += 1;
+        last_scanned = Some(key);
+        if let Some(event) = _get_event(&key.1).filter(|event| matches(event)) {
+            events.push(event);
+            if events.len() > limit {
                 break;
             }
         }
+    }
+
+    let next_cursor = if events.len() > limit {
+        events.truncate(limit);
+        events.last().map(|event| event_cursor(sort, event))
+    } else if scanned == MAX_EVENT_SCAN {
+        // Cut short, the next page continues after the last event looked at
+        last_scanned.map(|(start_date, id)| EventCursor {
+            key: EventSortKey::Time(start_date),
+            id,
+        })
+    } else {
+        None
+    };
+    EventPage {
+        events,
+        next_cursor,
+    }
+}
+
+// a helper method to get the events that may match a filter, looked up through the venue,
+// category or tag index when the filter has one.
+fn filter_candidates(filter: &EventFilter, tag: Option<&str>) -> Vec<Event> {
+    let event_ids: Vec<u64> = if let Some(venue_id) = filter.venue_id {
+        venue_event_ids(venue_id)
+    } else if let Some(category_id) = filter.category_id {
+        category_event_ids(category_id)
+    } else if let Some(tag) = tag {
+        tag_event_ids(tag)
+    } else {
+        return EVENTS_STORAGE
+            .with(|service| service.borrow().iter().map(|(_, event)| event).collect());
+    };
+    event_ids
+        .into_iter()
+        .filter_map(|event_id| _get_event(&event_id))
+        .collect()
+}
+
+// a helper method to get the position of an event in a sort, ties are broken by id.
+fn event_cursor(sort: EventSort, event: &Event) -> EventCursor {
+    let key = match sort {
+        EventSort::StartDate | EventSort::StartDateDesc => EventSortKey::Time(event.start_date),
+        EventSort::Name => EventSortKey::Name(event.event_name.to_lowercase()),
+        EventSort::Newest => EventSortKey::Time(event.timestamp),
+    };
+    EventCursor { key, id: event.id }
+}
+
+// a helper method to order two positions in a sort, descending sorts reverse the id as well.
+fn compare_cursors(sort: EventSort, a: &EventCursor, b: &EventCursor) -> std::cmp::Ordering {
+    match sort {
+        EventSort::StartDate | EventSort::Name => a.cmp(b),
+        EventSort::StartDateDesc | EventSort::Newest => b.cmp(a),
+    }
+}
+
+// a helper method to get the listed events matching a filter, sorted by start date.
+// Drafts and cancelled events are left out.
+fn get_events_sorted(filter: impl Fn(&Event) -> bool) -> Vec<Event> {
+    let mut events: Vec<Event> = EVENTS_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| {
+                !matches!(event.status, EventStatus::Draft | EventStatus::Cancelled)
+                    && filter(event)
+            })
+            .collect()
+    });
+    events.sort_by_key(|event| (event.start_date, event.id));
+    events
+}
+
+#[ic_cdk::update]
+fn add_attendees(
+    event_id: u64,
+    attendee_payload: AttendeePayload,
+    session_token: Option<String>,
+) -> Result<(), Error> {
+    // Attendees are added by purchasing a ticket, organizers may add guests directly
+    let user = authorize(session_token, Permission::ManageEvent(event_id))?;
+
+    let summary = format!("added guest '{}'", attendee_payload.attendee_name);
+    do_add_attendee(event_id, attendee_payload)?;
+    audit(
+        Some(user.id),
+        "add_attendees",
+        AuditEntity::Event,
+        vec![event_id],
+        summary,
+    );
+    Ok(())
+}
+
+// helper method to add an attendee to an event.
+fn do_add_attendee(event_id: u64, attendee_payload: AttendeePayload) -> Result<(), Error> {
+    // Validate that all fields in the payload are filled
+    if attendee_payload.attendee_name.is_empty() {
+        return Err(Error::CustomError(
+            "All fields in the payload are required".to_string(),
+        ));
+    }
+    if attendee_payload.attendee_name.len() > MAX_ATTENDEE_NAME_LEN {
+        return Err(Error::CustomError(format!(
+            "Attendee name cannot be longer than {} bytes",
+            MAX_ATTENDEE_NAME_LEN
+        )));
+    }
+
+    match EVENTS_STORAGE.with(|service| {
+        let events_storage = service.borrow();
+        if events_storage.contains_key(&event_id) {
+            let attendee = Attendee {
+                attendee_name: attendee_payload.attendee_name.clone(), // Cloning the username field
+            };
+            // Attendees are appended after the last one of the event
+            let position = EVENT_ATTENDEES.with(|service| {
+                service
+                    .borrow()
+                    .iter_upper_bound(&(event_id, u64::MAX))
+                    .next()
+                    .filter(|((id, _), _)| *id == event_id)
+                    .map_or(0, |((_, position), _)| position + 1)
+            });
+            EVENT_ATTENDEES
+                .with(|service| service.borrow_mut().insert((event_id, position), attendee));
+            Ok(()) // Return Ok if successful
+        } else {
+            Err(Error::NotFound {
+                msg: format!("Event with id={} not found", event_id),
+            })
+        }
+    }) {
+        Ok(()) => {
+            // Return a success message
+            Ok(println!("Attendee successfully added to the event!"))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[ic_cdk::query]
+fn get_attendees(
+    event_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+    session_token: Option<String>,
+) -> Result<AttendeePage, Error> {
+    authorize_caller(session_token, Permission::ViewAttendees(event_id))?;
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    match EVENTS_STORAGE.with(|service| {
+        let events_storage = service.borrow();
+        if events_storage.contains_key(&event_id) {
+            // Attendees are only ever appended, so the count returned so far is a stable cursor
+            let start = cursor.unwrap_or(0);
+            let mut attendees: Vec<Attendee> = EVENT_ATTENDEES.with(|service| {
+                service
+                    .borrow()
+                    .range((event_id, start)..=(event_id, u64::MAX))
+                    .map(|(_, attendee)| attendee)
+                    .take(limit + 1)
+                    .collect()
+            });
+            let next_cursor = if attendees.len() > limit {
+                attendees.truncate(limit);
+                Some(start + limit as u64)
+            } else {
+                None
+            };
+            Ok(AttendeePage {
+                attendees,
+                next_cursor,
+            })
+        } else {
+            Err(Error::NotFound {
+                msg: format!("event with id={} not found", event_id),
+            })
+        }
+    }) {
+        Ok(attendees) => Ok(attendees),
+        Err(err) => Err(err),
+    }
+}
+
+// Ticket Queries
+#[ic_cdk::update]
+fn generate_tickets(
+    ticket_payload: TicketPayload,
+    session_token: Option<String>,
+) -> Result<Option<Vec<Ticket>>, Error> {
+    let event_id = ticket_payload.event_id;
+    let num_tickets = ticket_payload.num_tickets;
+
+    let user = authorize(session_token, Permission::ManageTickets(event_id))?;
+
+    match _get_event(&event_id) {
+        Some(event) => {
+            if matches!(
+                event.status,
+                EventStatus::Cancelled | EventStatus::Completed
+            ) {
+                return Err(Error::CustomError(format!(
+                    "Tickets cannot be added to a {:?} event",
+                    event.status
+                )));
+            }
+            if let Some(venue_id) = event.venue_id {
+                check_venue_capacity(venue_id, event_id, num_tickets as u64)?;
+            }
+            let mut tickets = Vec::new();
+            for _ in 0..num_tickets {
+                let id = ID_COUNTER
+                    .with(|counter| {
+                        let current_value = *counter.borrow().get();
+                        counter.borrow_mut().set(current_value + 1)
+                    })
+                    .expect("cannot increment id counter");
+                let new_ticket = Ticket {
+                    ticket_id: id,
+                    event_id: ticket_payload.event_id,
+                    ticket_type: match ticket_payload.ticket_type {
+                        TicketType::VVIP => "VVIP".to_string(),
+                        TicketType::VIP => "VIP".to_string(),
+                        TicketType::Regular => "Regular".to_string(),
+                        TicketType::Discount => "Discount".to_string(),
+                    },
+                    ticket_price: ticket_payload.ticket_price,
+                    num_tickets: 1,
+                    status: TicketStatus::Available,
+                    holder_id: None,
+                    checked_in_at: None,
+                };
+                do_insert_ticket(&new_ticket);
+                tickets.push(new_ticket);
+            }
+            audit(
+                Some(user.id),
+                "generate_tickets",
+                AuditEntity::Ticket,
+                tickets.iter().map(|ticket| ticket.ticket_id).collect(),
+                format!(
+                    "generated {} {:?} tickets at {} for event {}",
+                    num_tickets, ticket_payload.ticket_type, ticket_payload.ticket_price, event_id
+                ),
+            );
+            Ok(Some(tickets))
+        }
+        None => Ok(None),
+    }
+}
+
+#[ic_cdk::query]
+fn get_tickets(
+    event_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+    session_token: Option<String>,
+) -> Result<TicketPage, Error> {
+    authorize_caller(session_token, Permission::ViewTickets(event_id))?;
+    get_ticket_page(event_id, cursor, limit)
+}
+
+// Lists the tickets of an event for door staff, without prices or holders.
+#[ic_cdk::query]
+fn get_door_tickets(
+    event_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+    session_token: Option<String>,
+) -> Result<DoorTicketPage, Error> {
+    authorize(session_token, Permission::CheckInTickets(event_id))?;
+    let page = get_ticket_page(event_id, cursor, limit)?;
+    Ok(DoorTicketPage {
+        tickets: page.tickets.iter().map(Ticket::to_door_ticket).collect(),
+        next_cursor: page.next_cursor,
+    })
+}
+
+// a helper method to get a page of the tickets of an event.
+fn get_ticket_page(event_id: u64, cursor: Option<u64>, limit: u32) -> Result<TicketPage, Error> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    // Tickets are returned by id, continuing after the cursor ticket
+    let start = cursor.map_or(0, |ticket_id| ticket_id.saturating_add(1));
+    let ticket_ids: Vec<u64> = EVENT_TICKETS.with(|index| {
+        index
+            .borrow()
+            .range((event_id, start)..=(event_id, u64::MAX))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .take(limit + 1)
+            .collect()
+    });
+    let mut tickets: Vec<Ticket> = ticket_ids
+        .into_iter()
+        .filter_map(|ticket_id| TICKETS_STORAGE.with(|service| service.borrow().get(&ticket_id)))
+        .collect();
+
+    if tickets.is_empty() && cursor.is_none() {
+        // If no tickets found for the specified event_id, return an error
+        return Err(Error::NotFound {
+            msg: format!("No tickets found for event with ID: {}", event_id),
+        });
+    }
+
+    let next_cursor = if tickets.len() > limit {
+        tickets.truncate(limit);
+        tickets.last().map(|ticket| ticket.ticket_id)
+    } else {
+        None
+    };
+    Ok(TicketPage {
+        tickets,
+        next_cursor,
+    })
+}
+
+#[ic_cdk::update]
+fn delete_ticket(ticket_id: u64, session_token: Option<String>) -> Result<Ticket, Error> {
+    match TICKETS_STORAGE.with(|service| service.borrow().get(&ticket_id)) {
+        Some(ticket) => {
+            // Tickets of deleted events can only be cleaned up by admins
+            let user = authorize(session_token, Permission::ManageTickets(ticket.event_id))?;
+            // Sold tickets are records of payments, like in `delete_event`
+            if !matches!(
+                ticket.status,
+                TicketStatus::Available | TicketStatus::Voided
+            ) {
+                return Err(Error::CustomError(format!(
+                    "A {:?} ticket cannot be deleted",
+                    ticket.status
+                )));
+            }
+            do_remove_ticket(&ticket);
+            audit(
+                Some(user.id),
+                "delete_ticket",
+                AuditEntity::Ticket,
+                vec![ticket_id],
+                format!(
+                    "deleted {:?} {} ticket of event {}",
+                    ticket.status, ticket.ticket_type, ticket.event_id
+                ),
+            );
+            Ok(ticket)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "couldn't delete a ticket with id={}. ticket not found.",
+                ticket_id
+            ),
+        }),
+    }
+}
+
+// Looks up a ticket at the door, available to the event's organizers and scanners.
+#[ic_cdk::query]
+fn lookup_ticket(ticket_id: u64, session_token: Option<String>) -> Result<DoorTicket, Error> {
+    let ticket = get_ticket(ticket_id)?;
+    authorize(session_token, Permission::CheckInTickets(ticket.event_id))?;
+    Ok(ticket.to_door_ticket())
+}
+
+// Checks in a sold ticket, a ticket can only be checked in once.
+#[ic_cdk::update]
+fn check_in_ticket(ticket_id: u64, session_token: Option<String>) -> Result<DoorTicket, Error> {
+    let mut ticket = get_ticket(ticket_id)?;
+    let user = authorize(session_token, Permission::CheckInTickets(ticket.event_id))?;
+
+    match ticket.status {
+        TicketStatus::Sold => {
+            ticket.status = TicketStatus::CheckedIn;
+            ticket.checked_in_at = Some(time());
+            do_insert_ticket(&ticket);
+            audit(
+                Some(user.id),
+                "check_in_ticket",
+                AuditEntity::Ticket,
+                vec![ticket_id],
+                "status: Sold -> CheckedIn",
+            );
+            Ok(ticket.to_door_ticket())
+        }
+        TicketStatus::CheckedIn => Err(Error::CustomError(format!(
+            "Ticket with id={} is already checked in",
+            ticket_id
+        ))),
+        TicketStatus::Available => Err(Error::CustomError(format!(
+            "Ticket with id={} has not been sold",
+            ticket_id
+        ))),
+        TicketStatus::Voided | TicketStatus::Refundable => Err(Error::CustomError(format!(
+            "Ticket with id={} belongs to a cancelled event",
+            ticket_id
+        ))),
+    }
+}
+
+// Get the number of available tickets for a specific event and ticket type
+#[ic_cdk::query]
+fn get_available_tickets_count(event_id: u64, ticket_type: TicketType) -> usize {
+    get_event_tickets(event_id)
+        .iter()
+        .filter(|ticket| {
+            ticket.status == TicketStatus::Available
+                && ticket.ticket_type == ticket_type.to_string()
+        })
+        .map(|ticket| ticket.num_tickets as usize)
+        .sum()
+}
+
+// Ticket Purchase
+#[ic_cdk::update]
+fn purchase_ticket(
+    payload: TicketPurchasePayload,
+    session_token: Option<String>,
+) -> Result<(Vec<Ticket>, u64), Error> {
+    // Authenticate the buyer before any ticket is taken from the inventory. Tickets sold
+    // through a partner's API key have no holder account.
+    let buyer = authorize_caller(session_token, Permission::PurchaseTickets(payload.event_id))?;
+    let holder_id = match &buyer {
+        Caller::User(user) => {
+            if user.email_verified_at.is_none() {
+                return Err(Error::CustomError(
+                    "Verify your email before purchasing tickets".to_string(),
+                ));
+            }
+            Some(user.id)
+        }
+        Caller::ApiKey(_) => None,
+    };
+
+    // Validate that all fields in the payload are filled
+    if payload.attendee_name.is_empty() {
+        return Err(Error::CustomError(
+            "All fields in the payload are required".to_string(),
+        ));
+    }
+    // Checked before any ticket is sold, the attendee is added after
+    if payload.attendee_name.len() > MAX_ATTENDEE_NAME_LEN {
+        return Err(Error::CustomError(format!(
+            "Attendee name cannot be longer than {} bytes",
+            MAX_ATTENDEE_NAME_LEN
+        )));
+    }
+
+    let event_id = payload.event_id;
+    let ticket_type = payload.ticket_type.clone();
+    let attendee_name = payload.attendee_name;
+    let num_tickets = payload.num_tickets as usize; // Convert num_tickets to usize
+
+    // Check if the event exists
+    match _get_event(&event_id) {
+        Some(event) => {
+            if event.status != EventStatus::Published {
+                return Err(Error::CustomError(format!(
+                    "Tickets are not on sale for a {:?} event",
+                    event.status
+                )));
+            }
+
+            // Check if there are enough tickets available
+            let available_tickets_count =
+                get_available_tickets_count(event_id, ticket_type.clone());
+            if available_tickets_count < num_tickets {
+                return Err(Error::CustomError(format!(
+                    "Not enough tickets available for type: {}",
+                    ticket_type
+                )));
+            }
+
+            // Fetch ticket price from the tickets of the event
+            let event_tickets = get_event_tickets(event_id);
+            let ticket_price = event_tickets
+                .iter()
+                // Find the ticket by ticket_type
+                .find(|ticket| ticket.ticket_type == ticket_type.to_string())
+                .map(|ticket| ticket.ticket_price)
+                .unwrap_or_else(|| {
+                    // If the ticket price is not found, return a default price or handle the error as needed
+                    ic_cdk::print(
+                        "Ticket price not found for the specified ticket type and event ID.",
+                    );
+                    0
+                });
+
+            // Calculate total cost
+            let total_cost = ticket_price * num_tickets as u64;
+
+            // Create tickets for the purchase
+            let mut available = event_tickets.into_iter().filter(|ticket| {
+                ticket.status == TicketStatus::Available
+                    && ticket.ticket_type == ticket_type.to_string()
+            });
+            let mut tickets = Vec::new();
+            for _ in 0..num_tickets {
+                // Take the first available ticket of the ticket_type
+                let ticket = available.next();
+
+                if let Some(mut ticket) = ticket {
+                    // Sold tickets stay in storage so they can be checked in at the door
+                    ticket.status = TicketStatus::Sold;
+                    ticket.holder_id = holder_id;
+                    do_insert_ticket(&ticket);
+                    tickets.push(ticket);
+                } else {
+                    // Handle the case where a ticket is not found (should not occur if availability is properly checked)
+                    return Err(Error::CustomError(format!(
+                        "Failed to find available ticket for type: {}",
+                        ticket_type
+                    )));
+                }
+            }
+
+            // Update the event with the attendee's name
+            let summary = format!(
+                "sold {} {} tickets of event {} to '{}' for {}",
+                num_tickets, ticket_type, event_id, attendee_name, total_cost
+            );
+            do_add_attendee(
+                event_id,
+                AttendeePayload {
+                    attendee_name: attendee_name,
+                },
+            )?;
+            audit_caller(
+                &buyer,
+                "purchase_ticket",
+                AuditEntity::Ticket,
+                tickets.iter().map(|ticket| ticket.ticket_id).collect(),
+                summary,
+            );
+
+            // Return purchased tickets and total cost
+            Ok((tickets, total_cost))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Event with ID {} not found.", event_id),
+        }),
+    }
+}
+
+// helper method to perform insert for events.
+fn do_insert_event(event: &Event) {
+    let previous =
+        EVENTS_STORAGE.with(|service| service.borrow_mut().insert(event.id, event.clone()));
+    update_search_index(event.id, previous.as_ref(), Some(event));
+    update_taxonomy_indexes(event.id, previous.as_ref(), Some(event));
+    update_venue_index(event.id, previous.as_ref(), Some(event));
+    update_date_index(event.id, previous.as_ref(), Some(event));
+}
+
+// helper method to perform insert for tickets, keeping the event index in sync.
+fn do_insert_ticket(ticket: &Ticket) {
+    TICKETS_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(ticket.ticket_id, ticket.clone())
+    });
+    EVENT_TICKETS.with(|index| {
+        index
+            .borrow_mut()
+            .insert((ticket.event_id, ticket.ticket_id), ())
     });
+}
 
-    // Check if an event with the provided username was found
-    if let Some(event) = found_event {
-        Ok(Some(event))
+// helper method to remove a ticket and its index entry.
+fn do_remove_ticket(ticket: &Ticket) {
+    TICKETS_STORAGE.with(|service| service.borrow_mut().remove(&ticket.ticket_id));
+    EVENT_TICKETS.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(ticket.event_id, ticket.ticket_id))
+    });
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
+enum Error {
+    NotFound { msg: String },
+    AlreadyExists { msg: String },
+    CustomError(String),
+}
+
+// a helper method to get a ticket by id.
+fn get_ticket(ticket_id: u64) -> Result<Ticket, Error> {
+    match TICKETS_STORAGE.with(|service| service.borrow().get(&ticket_id)) {
+        Some(ticket) => Ok(ticket),
+        None => Err(Error::NotFound {
+            msg: format!("a ticket with id={} not found", ticket_id),
+        }),
+    }
+}
+
+// Parses and validates the dates of an event payload into start and end instants.
+// Whole days run until the end of the end date, so a one-day event may start and end on it.
+fn parse_event_dates(payload: &EventPayload) -> Result<(u64, u64, String), Error> {
+    let timezone_name = payload
+        .timezone
+        .as_deref()
+        .unwrap_or(DEFAULT_TIMEZONE)
+        .trim();
+    let timezone: Tz = timezone_name
+        .parse()
+        .map_err(|_| Error::CustomError(format!("Unknown timezone '{}'", timezone_name)))?;
+
+    let start_date = parse_event_instant(&payload.start_date, timezone, false)?;
+    let end_date = parse_event_instant(&payload.end_date, timezone, true)?;
+    if end_date <= start_date {
+        return Err(Error::CustomError(
+            "End date must be after the start date".to_string(),
+        ));
+    }
+    Ok((start_date, end_date, timezone.name().to_string()))
+}
+
+// Accepts RFC 3339 date-times with an offset, ISO 8601 local date-times and dates in the
+// event's timezone, and the older DD-MM-YYYY format.
+fn parse_event_instant(value: &str, timezone: Tz, end_of_day: bool) -> Result<u64, Error> {
+    let value = value.trim();
+    let invalid = || Error::CustomError(format!("'{}' is not a valid date", value));
+
+    let instant = if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        date_time.with_timezone(&Utc)
     } else {
-        Err(Error::NotFound {
-            msg: format!("Event with name '{}' not found", event_name),
+        let local = [
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M",
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%d %H:%M",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            let date = parse_date(value)?;
+            let date = if end_of_day { date.succ_opt()? } else { date };
+            date.and_hms_opt(0, 0, 0)
         })
+        .ok_or_else(invalid)?;
+        match timezone.from_local_datetime(&local) {
+            LocalResult::Single(date_time) | LocalResult::Ambiguous(date_time, _) => {
+                date_time.with_timezone(&Utc)
+            }
+            LocalResult::None => {
+                return Err(Error::CustomError(format!(
+                    "'{}' does not exist in {}, the clocks skip it",
+                    value, timezone
+                )))
+            }
+        }
+    };
+
+    instant
+        .timestamp_nanos_opt()
+        .and_then(|nanos| u64::try_from(nanos).ok())
+        .ok_or_else(|| Error::CustomError(format!("'{}' is out of the supported range", value)))
+}
+
+// Parses an ISO 8601 date or the older DD-MM-YYYY format.
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    ["%Y-%m-%d", "%d-%m-%Y"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+}
+
+// Formats an instant as RFC 3339 in the given timezone.
+fn format_instant(nanos: u64, timezone: &str) -> String {
+    let instant = Utc.timestamp_nanos(nanos as i64);
+    match timezone.parse::<Tz>() {
+        Ok(timezone) => instant.with_timezone(&timezone).to_rfc3339(),
+        Err(_) => instant.to_rfc3339(),
     }
 }
 
 #[ic_cdk::query]
-fn get_events() -> Result<Vec<Event>, Error> {
-    let events = EVENTS_STORAGE.with(|service| {
-        let borrowed_service = service.borrow();
-        let events: Vec<Event> = borrowed_service
-            .iter()
-            .map(|(_, event)| event.clone())
-            .collect();
+fn get_venue(venue_id: u64) -> Result<Venue, Error> {
+    load_venue(venue_id)
+}
 
-        events
+// Venues by id, `city` narrows them down ignoring case.
+#[ic_cdk::query]
+fn list_venues(city: Option<String>, cursor: Option<u64>, limit: u32) -> VenuePage {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let city = city.map(|city| city.trim().to_lowercase());
+    let start = cursor.map_or(0, |venue_id| venue_id.saturating_add(1));
+    let mut venues: Vec<Venue> = VENUES.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .map(|(_, venue)| venue)
+            .filter(|venue| {
+                city.as_ref()
+                    .is_none_or(|city| venue.address.city.to_lowercase() == *city)
+            })
+            .take(limit + 1)
+            .collect()
     });
-
-    if events.is_empty() {
-        Err(Error::CustomError("No events available".to_string()))
+    let next_cursor = if venues.len() > limit {
+        venues.truncate(limit);
+        venues.last().map(|venue| venue.id)
     } else {
-        Ok(events)
+        None
+    };
+    VenuePage {
+        venues,
+        next_cursor,
     }
 }
 
-#[ic_cdk::query]
-fn get_upcoming_events() -> Result<Vec<Event>, Error> {
-    let current_time = time();
+#[ic_cdk::update]
+fn create_venue(payload: VenuePayload, session_token: Option<String>) -> Result<Venue, Error> {
+    let user = authorize(session_token, Permission::CreateEvent)?;
+    validate_venue(&payload)?;
 
-    let upcoming_events = EVENTS_STORAGE.with(|service| {
-        let borrowed_service = service.borrow();
-        let events: Vec<Event> = borrowed_service
-            .iter()
-            .filter(|(_, event)| event.timestamp <= current_time)
-            .map(|(_, event)| event.clone())
-            .collect();
+    let id = VENUE_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    let venue = Venue {
+        id,
+        name: payload.name.trim().to_string(),
+        address: payload.address,
+        latitude: payload.latitude,
+        longitude: payload.longitude,
+        capacity: payload.capacity,
+        accessibility: payload.accessibility,
+        created_by: user.id,
+        created_at: time(),
+    };
+    VENUES.with(|service| service.borrow_mut().insert(id, venue.clone()));
+    update_geo_index(id, None, Some(&venue));
+    audit(
+        Some(user.id),
+        "create_venue",
+        AuditEntity::Venue,
+        vec![id],
+        format!("created '{}' for {} people", venue.name, venue.capacity),
+    );
+    Ok(venue)
+}
 
-        if events.is_empty() {
-            Err(Error::CustomError(
-                "No upcoming events available".to_string(),
-            ))
-        } else {
-            Ok(events)
+// Capacity can't be reduced below the tickets already issued for an upcoming event at the venue.
+#[ic_cdk::update]
+fn update_venue(
+    venue_id: u64,
+    payload: VenuePayload,
+    session_token: Option<String>,
+) -> Result<Venue, Error> {
+    let user = authorize(session_token, Permission::ManageVenue(venue_id))?;
+    let mut venue = load_venue(venue_id)?;
+    validate_venue(&payload)?;
+    for event in venue_event_ids(venue_id)
+        .into_iter()
+        .filter_map(|event_id| _get_event(&event_id))
+        .filter(|event| {
+            !matches!(
+                event.status,
+                EventStatus::Cancelled | EventStatus::Completed
+            )
+        })
+    {
+        let issued = issued_ticket_count(event.id);
+        if issued > payload.capacity {
+            return Err(Error::CustomError(format!(
+                "Event '{}' already has {} tickets, more than the new capacity of {}",
+                event.event_name, issued, payload.capacity
+            )));
+        }
+    }
+
+    let summary = diff_summary(&[
+        ("name", venue.name.clone(), payload.name.trim().to_string()),
+        (
+            "address",
+            format_address(&venue.address),
+            format_address(&payload.address),
+        ),
+        (
+            "coordinates",
+            format!("{}, {}", venue.latitude, venue.longitude),
+            format!("{}, {}", payload.latitude, payload.longitude),
+        ),
+        (
+            "capacity",
+            venue.capacity.to_string(),
+            payload.capacity.to_string(),
+        ),
+    ]);
+    let previous = venue.clone();
+    venue.name = payload.name.trim().to_string();
+    venue.address = payload.address;
+    venue.latitude = payload.latitude;
+    venue.longitude = payload.longitude;
+    venue.capacity = payload.capacity;
+    venue.accessibility = payload.accessibility;
+    VENUES.with(|service| service.borrow_mut().insert(venue_id, venue.clone()));
+    update_geo_index(venue_id, Some(&previous), Some(&venue));
+    audit(
+        Some(user.id),
+        "update_venue",
+        AuditEntity::Venue,
+        vec![venue_id],
+        summary,
+    );
+    Ok(venue)
+}
+
+// Venues can only be deleted once no event, not even a draft, takes place at them.
+#[ic_cdk::update]
+fn delete_venue(venue_id: u64, session_token: Option<String>) -> Result<Venue, Error> {
+    let user = authorize(session_token, Permission::ManageVenue(venue_id))?;
+    let venue = load_venue(venue_id)?;
+    let event_count = venue_event_ids(venue_id).len();
+    if event_count > 0 {
+        return Err(Error::CustomError(format!(
+            "Venue '{}' still has {} events",
+            venue.name, event_count
+        )));
+    }
+
+    VENUES.with(|service| service.borrow_mut().remove(&venue_id));
+    update_geo_index(venue_id, Some(&venue), None);
+    audit(
+        Some(user.id),
+        "delete_venue",
+        AuditEntity::Venue,
+        vec![venue_id],
+        format!("deleted '{}'", venue.name),
+    );
+    Ok(venue)
+}
+
+// a helper method to get a venue by id.
+fn load_venue(venue_id: u64) -> Result<Venue, Error> {
+    VENUES
+        .with(|service| service.borrow().get(&venue_id))
+        .ok_or(Error::NotFound {
+            msg: format!("venue with id={} not found", venue_id),
+        })
+}
+
+// a helper method to validate a venue payload.
+fn validate_venue(payload: &VenuePayload) -> Result<(), Error> {
+    let address = &payload.address;
+    if payload.name.trim().is_empty()
+        || address.street.trim().is_empty()
+        || address.city.trim().is_empty()
+        || address.country.trim().is_empty()
+    {
+        return Err(Error::CustomError(
+            "Venue name, street, city and country are required".to_string(),
+        ));
+    }
+    let too_long = [
+        &payload.name,
+        &address.street,
+        &address.city,
+        &address.region,
+        &address.postal_code,
+        &address.country,
+    ]
+    .iter()
+    .any(|field| field.chars().count() > MAX_VENUE_FIELD_LEN);
+    if too_long {
+        return Err(Error::CustomError(format!(
+            "Venue name and address fields cannot be more than {} characters",
+            MAX_VENUE_FIELD_LEN
+        )));
+    }
+    if payload.accessibility.notes.chars().count() > MAX_ACCESSIBILITY_NOTES_LEN {
+        return Err(Error::CustomError(format!(
+            "Accessibility notes cannot be more than {} characters",
+            MAX_ACCESSIBILITY_NOTES_LEN
+        )));
+    }
+    if !(-90.0..=90.0).contains(&payload.latitude) || !(-180.0..=180.0).contains(&payload.longitude)
+    {
+        return Err(Error::CustomError(
+            "Latitude must be between -90 and 90 and longitude between -180 and 180".to_string(),
+        ));
+    }
+    if payload.capacity == 0 {
+        return Err(Error::CustomError(
+            "Venue capacity must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// a helper method to format an address on one line.
+fn format_address(address: &Address) -> String {
+    [
+        &address.street,
+        &address.postal_code,
+        &address.city,
+        &address.region,
+        &address.country,
+    ]
+    .iter()
+    .filter(|part| !part.is_empty())
+    .map(|part| part.as_str())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+// a helper method to validate the venue of an event payload. Events at a venue without a
+// location of their own are located at the venue.
+fn parse_event_venue(
+    venue_id: Option<u64>,
+    location: &str,
+) -> Result<(Option<u64>, String), Error> {
+    match venue_id {
+        Some(venue_id) => {
+            let venue = load_venue(venue_id)?;
+            let location = if location.is_empty() {
+                // Venue fields are limited in characters, the location in bytes
+                let mut location = format!("{}, {}", venue.name, venue.address.city);
+                let mut end = MAX_EVENT_LOCATION_LEN.min(location.len());
+                while !location.is_char_boundary(end) {
+                    end -= 1;
+                }
+                location.truncate(end);
+                location
+            } else {
+                location.to_string()
+            };
+            Ok((Some(venue_id), location))
+        }
+        None => Ok((None, location.to_string())),
+    }
+}
+
+// Checks that an event at a venue can have `additional` more tickets.
+fn check_venue_capacity(venue_id: u64, event_id: u64, additional: u64) -> Result<(), Error> {
+    let venue = load_venue(venue_id)?;
+    let issued = issued_ticket_count(event_id);
+    if issued + additional > venue.capacity {
+        return Err(Error::CustomError(format!(
+            "'{}' holds {} people, the event already has {} tickets",
+            venue.name, venue.capacity, issued
+        )));
+    }
+    Ok(())
+}
+
+// a helper method to count the tickets of an event that haven't been voided.
+fn issued_ticket_count(event_id: u64) -> u64 {
+    get_event_tickets(event_id)
+        .iter()
+        .filter(|ticket| ticket.status != TicketStatus::Voided)
+        .map(|ticket| ticket.num_tickets as u64)
+        .sum()
+}
+
+// a helper method to get the ids of the events at a venue, drafts included.
+fn venue_event_ids(venue_id: u64) -> Vec<u64> {
+    VENUE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((venue_id, 0)..=(venue_id, u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
+}
+
+// Moves an event in the venue index when its venue changes.
+fn update_venue_index(event_id: u64, previous: Option<&Event>, event: Option<&Event>) {
+    let previous_venue = previous.and_then(|event| event.venue_id);
+    let venue = event.and_then(|event| event.venue_id);
+    if previous_venue == venue {
+        return;
+    }
+    VENUE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(venue_id) = previous_venue {
+            index.remove(&(venue_id, event_id));
+        }
+        if let Some(venue_id) = venue {
+            index.insert((venue_id, event_id), ());
         }
     });
+}
 
-    upcoming_events
+// Moves the start date index entry of an event when its start date changes.
+fn update_date_index(event_id: u64, previous: Option<&Event>, event: Option<&Event>) {
+    let previous_date = previous.map(|event| event.start_date);
+    let date = event.map(|event| event.start_date);
+    if previous_date == date {
+        return;
+    }
+    EVENT_DATE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(start_date) = previous_date {
+            index.remove(&(start_date, event_id));
+        }
+        if let Some(start_date) = date {
+            index.insert((start_date, event_id), ());
+        }
+    });
 }
 
+// Listed events at venues within `radius_km` of a point, closest first. Events without a venue
+// have no coordinates and are never returned.
 #[ic_cdk::query]
-fn get_past_events() -> Result<Vec<Event>, Error> {
-    let current_time = time();
+fn find_events_near(
+    latitude: f64,
+    longitude: f64,
+    radius_km: f64,
+    date_range: DateRange,
+) -> Result<Vec<NearbyEvent>, Error> {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(Error::CustomError(
+            "Latitude must be between -90 and 90 and longitude between -180 and 180".to_string(),
+        ));
+    }
+    if !(radius_km > 0.0 && radius_km <= MAX_NEARBY_RADIUS_KM) {
+        return Err(Error::CustomError(format!(
+            "Radius must be more than 0 and at most {} km",
+            MAX_NEARBY_RADIUS_KM
+        )));
+    }
 
-    let past_events = EVENTS_STORAGE.with(|service| {
-        let borrowed_service = service.borrow();
-        let events: Vec<Event> = borrowed_service
+    let mut venue_ids: Vec<u64> = GEO_INDEX.with(|index| {
+        let index = index.borrow();
+        geohash_search_cells(latitude, longitude, radius_km)
             .iter()
-            .filter(|(_, event)| event.timestamp > current_time)
-            .map(|(_, event)| event.clone())
-            .collect();
+            .flat_map(|cell| {
+                index
+                    .range((StringKey(cell.clone()), 0)..)
+                    .take_while(|((geohash, _), _)| geohash.0.starts_with(cell.as_str()))
+                    .map(|((_, venue_id), _)| venue_id)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+    venue_ids.sort();
+    venue_ids.dedup();
 
-        if events.is_empty() {
-            Err(Error::CustomError("No past events available".to_string()))
-        } else {
-            Ok(events)
+    let mut events = Vec::new();
+    for venue in venue_ids
+        .into_iter()
+        .filter_map(|venue_id| load_venue(venue_id).ok())
+    {
+        let distance_km = distance_km(latitude, longitude, venue.latitude, venue.longitude);
+        if distance_km > radius_km {
+            continue;
+        }
+        for event in venue_event_ids(venue.id)
+            .into_iter()
+            .filter_map(|event_id| _get_event(&event_id))
+            .filter(|event| {
+                // Like the date lists, drafts and cancelled events are left out
+                !matches!(event.status, EventStatus::Draft | EventStatus::Cancelled)
+                    && date_range.from.is_none_or(|from| event.end_date > from)
+                    && date_range.to.is_none_or(|to| event.start_date < to)
+            })
+        {
+            events.push(NearbyEvent {
+                event,
+                venue: venue.clone(),
+                distance_km,
+            });
         }
+    }
+    events.sort_by(|a, b| {
+        a.distance_km
+            .total_cmp(&b.distance_km)
+            .then(a.event.start_date.cmp(&b.event.start_date))
+            .then(a.event.id.cmp(&b.event.id))
     });
+    events.truncate(MAX_NEARBY_EVENTS);
+    Ok(events)
+}
 
-    past_events
+// Encodes coordinates as a geohash, nearby points share a prefix.
+fn geohash(latitude: f64, longitude: f64, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    // Bits alternate between longitude and latitude, starting with longitude
+    let mut longitude_bit = true;
+    for _ in 0..precision {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (range, value): (&mut (f64, f64), f64) = if longitude_bit {
+                (&mut lon_range, longitude)
+            } else {
+                (&mut lat_range, latitude)
+            };
+            let middle = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= middle {
+                index |= 1;
+                range.0 = middle;
+            } else {
+                range.1 = middle;
+            }
+            longitude_bit = !longitude_bit;
+        }
+        hash.push(GEOHASH_ALPHABET[index] as char);
+    }
+    hash
 }
 
-#[ic_cdk::update]
-fn add_attendees(
-    payload: UserPayload1,
-    event_id: u64,
-    attendee_payload: AttendeePayload,
-) -> Result<(), Error> {
-    // Authenticate the user
-    let _user = authenticate_user(payload.username.clone(), payload.password.clone())?;
+// The geohash prefixes covering a circle: the cell of the center and its eight neighbours, at
+// the finest precision whose cells are at least as large as the radius. Close to the poles no
+// cell is wide enough and the empty prefix covers every venue.
+fn geohash_search_cells(latitude: f64, longitude: f64, radius_km: f64) -> Vec<String> {
+    // Cells are narrowest on the edge of the circle closest to a pole
+    let farthest_latitude = (latitude.abs() + radius_km / KM_PER_DEGREE).min(90.0);
+    for precision in (1..=GEOHASH_PRECISION).rev() {
+        let cell_height = 180.0 / 2f64.powi((5 * precision / 2) as i32);
+        let cell_width = 360.0 / 2f64.powi((5 * precision).div_ceil(2) as i32);
+        let height_km = cell_height * KM_PER_DEGREE;
+        let width_km = cell_width * KM_PER_DEGREE * farthest_latitude.to_radians().cos();
+        if height_km < radius_km || width_km < radius_km {
+            continue;
+        }
 
-    // Validate that all fields in the payload are filled
-    if payload.username.is_empty()
-        || payload.password.is_empty()
-        || attendee_payload.attendee_name.is_empty()
-    {
-        return Err(Error::CustomError(
-            "All fields in the payload are required".to_string(),
-        ));
+        let mut cells = Vec::new();
+        for lat_offset in [-cell_height, 0.0, cell_height] {
+            let cell_latitude = latitude + lat_offset;
+            if !(-90.0..=90.0).contains(&cell_latitude) {
+                continue;
+            }
+            for lon_offset in [-cell_width, 0.0, cell_width] {
+                // Longitudes wrap around the antimeridian
+                let cell_longitude = (longitude + lon_offset + 540.0).rem_euclid(360.0) - 180.0;
+                cells.push(geohash(cell_latitude, cell_longitude, precision));
+            }
+        }
+        cells.sort();
+        cells.dedup();
+        return cells;
     }
+    vec![String::new()]
+}
 
-    match EVENTS_STORAGE.with(|service| {
-        let mut events_storage = service.borrow_mut();
-        if let Some(event) = events_storage.get(&event_id) {
-            // Use get to get a reference to the event
-            let attendee = Attendee {
-                attendee_name: attendee_payload.attendee_name.clone(), // Cloning the username field
-            };
-            let mut cloned_event = event.clone(); // Cloning the event to modify it
-            cloned_event.attendees.push(attendee); // Add the new attendee to the cloned event's attendees vector
-            events_storage.insert(event_id, cloned_event); // Replace the old event with the modified one
-            Ok(()) // Return Ok if successful
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Event with id={} not found", event_id),
-            })
+// Great-circle distance between two points in kilometers.
+fn distance_km(latitude: f64, longitude: f64, other_latitude: f64, other_longitude: f64) -> f64 {
+    let d_lat = (other_latitude - latitude).to_radians();
+    let d_lon = (other_longitude - longitude).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + latitude.to_radians().cos()
+            * other_latitude.to_radians().cos()
+            * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+// Moves a venue in the geohash index when its coordinates change.
+fn update_geo_index(venue_id: u64, previous: Option<&Venue>, venue: Option<&Venue>) {
+    let geohash_of = |venue: &Venue| geohash(venue.latitude, venue.longitude, GEOHASH_PRECISION);
+    let previous_hash = previous.map(geohash_of);
+    let hash = venue.map(geohash_of);
+    if previous_hash == hash {
+        return;
+    }
+    GEO_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous_hash) = previous_hash {
+            index.remove(&(StringKey(previous_hash), venue_id));
         }
-    }) {
-        Ok(()) => {
-            // Return a success message
-            Ok(println!("Attendee successfully added to the event!"))
+        if let Some(hash) = hash {
+            index.insert((StringKey(hash), venue_id), ());
         }
-        Err(err) => Err(err),
+    });
+}
+
+// Indexes the venues stored before the geohash index existed.
+fn backfill_geo_index() {
+    if !GEO_INDEX.with(|index| index.borrow().is_empty()) {
+        return;
     }
+    let venues: Vec<Venue> =
+        VENUES.with(|service| service.borrow().iter().map(|(_, venue)| venue).collect());
+    for venue in venues {
+        update_geo_index(venue.id, None, Some(&venue));
+    }
+}
+
+// Listed events of a category, soonest first.
+#[ic_cdk::query]
+fn get_events_by_category(
+    category_id: u64,
+    cursor: Option<EventCursor>,
+    limit: u32,
+) -> Result<EventPage, Error> {
+    load_category(category_id)?;
+    let filter = EventFilter {
+        category_id: Some(category_id),
+        ..Default::default()
+    };
+    list_events(filter, EventSort::StartDate, cursor, limit)
+}
+
+// Listed events with a tag, soonest first.
+#[ic_cdk::query]
+fn get_events_by_tag(
+    tag: String,
+    cursor: Option<EventCursor>,
+    limit: u32,
+) -> Result<EventPage, Error> {
+    let filter = EventFilter {
+        tag: Some(tag),
+        ..Default::default()
+    };
+    list_events(filter, EventSort::StartDate, cursor, limit)
+}
+
+// Every category by name, with the number of listed events in it.
+#[ic_cdk::query]
+fn list_categories() -> Vec<CategorySummary> {
+    let mut categories: Vec<CategorySummary> = CATEGORIES
+        .with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, category)| category)
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .map(|category| {
+            let event_count = category_event_ids(category.id)
+                .into_iter()
+                .filter_map(|event_id| _get_event(&event_id))
+                .filter(|event| event.status != EventStatus::Draft)
+                .count() as u64;
+            CategorySummary {
+                category,
+                event_count,
+            }
+        })
+        .collect();
+    categories.sort_by_key(|summary| summary.category.name.to_lowercase());
+    categories
+}
+
+#[ic_cdk::update]
+fn create_category(
+    payload: CategoryPayload,
+    session_token: Option<String>,
+) -> Result<Category, Error> {
+    let user = authorize_controller_or(session_token, Permission::ManageCategories)?;
+    let (name, description) = validate_category(&payload, None)?;
+
+    let id = CATEGORY_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter");
+    let category = Category {
+        id,
+        name,
+        description,
+        created_at: time(),
+    };
+    CATEGORIES.with(|service| service.borrow_mut().insert(id, category.clone()));
+    audit(
+        user.map(|user| user.id),
+        "create_category",
+        AuditEntity::Category,
+        vec![id],
+        format!("created '{}'", category.name),
+    );
+    Ok(category)
 }
 
-#[ic_cdk::query]
-fn get_attendees(event_id: u64) -> Result<Vec<Attendee>, Error> {
-    match EVENTS_STORAGE.with(|service| {
-        let events_storage = service.borrow();
-        if let Some(event) = events_storage.get(&event_id) {
-            Ok(event.attendees.clone()) // Return a clone of the attendees vector
-        } else {
-            Err(Error::NotFound {
-                msg: format!("event with id={} not found", event_id),
-            })
-        }
-    }) {
-        Ok(attendees) => Ok(attendees),
-        Err(err) => Err(err),
-    }
+#[ic_cdk::update]
+fn update_category(
+    category_id: u64,
+    payload: CategoryPayload,
+    session_token: Option<String>,
+) -> Result<Category, Error> {
+    let user = authorize_controller_or(session_token, Permission::ManageCategories)?;
+    let mut category = load_category(category_id)?;
+    let (name, description) = validate_category(&payload, Some(category_id))?;
+
+    let summary = diff_summary(&[
+        ("name", category.name.clone(), name.clone()),
+        (
+            "description",
+            category.description.clone(),
+            description.clone(),
+        ),
+    ]);
+    category.name = name;
+    category.description = description;
+    CATEGORIES.with(|service| service.borrow_mut().insert(category_id, category.clone()));
+    audit(
+        user.map(|user| user.id),
+        "update_category",
+        AuditEntity::Category,
+        vec![category_id],
+        summary,
+    );
+    Ok(category)
 }
 
-// Ticket Queries
+// Categories can only be deleted once no event, not even a draft, is in them.
 #[ic_cdk::update]
-fn generate_tickets(
-    ticket_payload: TicketPayload,
-    payload: UserPayload1,
-) -> Result<Option<Vec<Ticket>>, Error> {
-    // Clone the username before passing it to authenticate_admin
-    let username = payload.username.clone();
-    let password = payload.password.clone();
+fn delete_category(category_id: u64, session_token: Option<String>) -> Result<Category, Error> {
+    let user = authorize_controller_or(session_token, Permission::ManageCategories)?;
+    let category = load_category(category_id)?;
+    let event_count = category_event_ids(category_id).len();
+    if event_count > 0 {
+        return Err(Error::CustomError(format!(
+            "Category '{}' still has {} events",
+            category.name, event_count
+        )));
+    }
 
-    let _user = authenticate_admin(username, password)?;
+    CATEGORIES.with(|service| service.borrow_mut().remove(&category_id));
+    audit(
+        user.map(|user| user.id),
+        "delete_category",
+        AuditEntity::Category,
+        vec![category_id],
+        format!("deleted '{}'", category.name),
+    );
+    Ok(category)
+}
 
-    let event_id = ticket_payload.event_id;
-    let num_tickets = ticket_payload.num_tickets;
+// a helper method to get a category by id.
+fn load_category(category_id: u64) -> Result<Category, Error> {
+    CATEGORIES
+        .with(|service| service.borrow().get(&category_id))
+        .ok_or(Error::NotFound {
+            msg: format!("category with id={} not found", category_id),
+        })
+}
 
-    match _get_event(&event_id) {
-        Some(_) => {
-            let mut tickets = Vec::new();
-            for _ in 0..num_tickets {
-                let id = ID_COUNTER
-                    .with(|counter| {
-                        let current_value = *counter.borrow().get();
-                        counter.borrow_mut().set(current_value + 1)
-                    })
-                    .expect("cannot increment id counter");
-                let new_ticket = Ticket {
-                    ticket_id: id,
-                    event_id: ticket_payload.event_id,
-                    ticket_type: match ticket_payload.ticket_type {
-                        TicketType::VVIP => "VVIP".to_string(),
-                        TicketType::VIP => "VIP".to_string(),
-                        TicketType::Regular => "Regular".to_string(),
-                        TicketType::Discount => "Discount".to_string(),
-                    },
-                    ticket_price: ticket_payload.ticket_price,
-                    num_tickets: 1,
-                };
-                do_insert_ticket(&new_ticket);
-                tickets.push(new_ticket);
-            }
-            Ok(Some(tickets))
+// a helper method to validate a category, whose name has to be unique ignoring case.
+fn validate_category(
+    payload: &CategoryPayload,
+    category_id: Option<u64>,
+) -> Result<(String, String), Error> {
+    let name = payload.name.trim().to_string();
+    let description = payload.description.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_CATEGORY_NAME_LEN {
+        return Err(Error::CustomError(format!(
+            "Category name must be between 1 and {} characters",
+            MAX_CATEGORY_NAME_LEN
+        )));
+    }
+    if description.chars().count() > MAX_CATEGORY_DESCRIPTION_LEN {
+        return Err(Error::CustomError(format!(
+            "Category description cannot be more than {} characters",
+            MAX_CATEGORY_DESCRIPTION_LEN
+        )));
+    }
+    let taken = CATEGORIES.with(|service| {
+        service.borrow().iter().any(|(id, category)| {
+            Some(id) != category_id && category.name.to_lowercase() == name.to_lowercase()
+        })
+    });
+    if taken {
+        return Err(Error::AlreadyExists {
+            msg: format!("category '{}' already exists", name),
+        });
+    }
+    Ok((name, description))
+}
+
+// a helper method to check that the name, details and location of an event fit in storage.
+fn validate_event_text(event_name: &str, details: &str, location: &str) -> Result<(), Error> {
+    for (field, value, max_len) in [
+        ("Event name", event_name, MAX_EVENT_NAME_LEN),
+        ("Details", details, MAX_EVENT_DETAILS_LEN),
+        ("Location", location, MAX_EVENT_LOCATION_LEN),
+    ] {
+        if value.len() > max_len {
+            return Err(Error::CustomError(format!(
+                "{} cannot be longer than {} bytes",
+                field, max_len
+            )));
         }
-        None => Ok(None),
     }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn get_tickets(event_id: u64) -> Result<Vec<Ticket>, Error> {
-    TICKETS_STORAGE.with(|service| {
-        let borrowed_service = service.borrow();
-        let tickets: Vec<Ticket> = borrowed_service
-            .iter()
-            .filter(|(_, ticket)| ticket.event_id == event_id)
-            .map(|(_, ticket)| ticket.clone())
-            .collect();
+// a helper method to validate the category and tags of an event payload.
+fn parse_event_taxonomy(
+    category_id: Option<u64>,
+    raw_tags: Option<&[String]>,
+) -> Result<(Option<u64>, Vec<String>), Error> {
+    if let Some(category_id) = category_id {
+        load_category(category_id)?;
+    }
 
-        if tickets.is_empty() {
-            // If no tickets found for the specified event_id, return an error
-            return Err(Error::NotFound {
-                msg: format!("No tickets found for event with ID: {}", event_id),
-            });
+    let mut tags: Vec<String> = Vec::new();
+    for tag in raw_tags.into_iter().flatten() {
+        let tag = normalize_tag(tag)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
         }
-
-        Ok(tickets)
-    })
+    }
+    if tags.len() > MAX_EVENT_TAGS {
+        return Err(Error::CustomError(format!(
+            "An event cannot have more than {} tags",
+            MAX_EVENT_TAGS
+        )));
+    }
+    Ok((category_id, tags))
 }
 
-#[ic_cdk::update]
-fn delete_ticket(ticket_id: u64, payload: UserPayload1) -> Result<Ticket, Error> {
-    // Clone the username before passing it to authenticate_admin
-    let username = payload.username.clone();
-    let password = payload.password.clone();
+// Tags are compared in lowercase and may only contain letters, digits and dashes.
+fn normalize_tag(tag: &str) -> Result<String, Error> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty()
+        || tag.chars().count() > MAX_TAG_LEN
+        || !tag.chars().all(|c| c.is_alphanumeric() || c == '-')
+    {
+        return Err(Error::CustomError(format!(
+            "'{}' is not a valid tag: use up to {} letters, digits or dashes",
+            tag, MAX_TAG_LEN
+        )));
+    }
+    Ok(tag)
+}
 
-    let _user = authenticate_admin(username, password)?;
+// a helper method to get the tags of an event.
+fn event_tags(event: &Event) -> &[String] {
+    event.tags.as_deref().unwrap_or_default()
+}
 
-    match TICKETS_STORAGE.with(|service| service.borrow_mut().remove(&ticket_id)) {
-        Some(ticket) => Ok(ticket),
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't delete a ticket with id={}. ticket not found.",
-                ticket_id
-            ),
-        }),
-    }
+// a helper method to get the ids of the events in a category, drafts included.
+fn category_event_ids(category_id: u64) -> Vec<u64> {
+    CATEGORY_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((category_id, 0)..=(category_id, u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
+    })
 }
 
-// Get the number of available tickets for a specific event and ticket type
-#[ic_cdk::query]
-fn get_available_tickets_count(event_id: u64, ticket_type: TicketType) -> usize {
-    TICKETS_STORAGE.with(|service| {
-        let borrowed_service = service.borrow();
-        borrowed_service
-            .iter()
-            .filter(|(_, ticket)| {
-                ticket.event_id == event_id && &ticket.ticket_type == &ticket_type.to_string()
-            })
-            .map(|(_, ticket)| ticket.num_tickets as usize)
-            .sum()
+// a helper method to get the ids of the events with a tag, drafts included.
+fn tag_event_ids(tag: &str) -> Vec<u64> {
+    let key = StringKey(tag.to_string());
+    TAG_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((key.clone(), 0)..=(key, u64::MAX))
+            .map(|((_, event_id), _)| event_id)
+            .collect()
     })
 }
 
-// Ticket Purchase
-#[ic_cdk::update]
-fn purchase_ticket(
-    payload: TicketPurchasePayload,
-    payload1: UserPayload1,
-) -> Result<(Vec<Ticket>, u64), Error> {
-    // Validate that all fields in the payload are filled
-    if payload.attendee_name.is_empty() {
-        return Err(Error::CustomError(
-            "All fields in the payload are required".to_string(),
-        ));
+// Moves an event between the category and tag indexes when its category or tags change.
+fn update_taxonomy_indexes(event_id: u64, previous: Option<&Event>, event: Option<&Event>) {
+    let previous_category = previous.and_then(|event| event.category_id);
+    let category = event.and_then(|event| event.category_id);
+    if previous_category != category {
+        CATEGORY_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            if let Some(category_id) = previous_category {
+                index.remove(&(category_id, event_id));
+            }
+            if let Some(category_id) = category {
+                index.insert((category_id, event_id), ());
+            }
+        });
     }
 
-    let event_id = payload.event_id;
-    let ticket_type = payload.ticket_type.clone();
-    let attendee_name = payload.attendee_name;
-    let num_tickets = payload.num_tickets as usize; // Convert num_tickets to usize
-
-    // Check if the event exists
-    match _get_event(&event_id) {
-        Some(_event) => {
-            // Check if there are enough tickets available
-            let available_tickets_count =
-                get_available_tickets_count(event_id, ticket_type.clone());
-            if available_tickets_count < num_tickets {
-                return Err(Error::CustomError(format!(
-                    "Not enough tickets available for type: {}",
-                    ticket_type
-                )));
+    let previous_tags = previous.map(event_tags).unwrap_or_default();
+    let tags = event.map(event_tags).unwrap_or_default();
+    if previous_tags != tags {
+        TAG_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for tag in previous_tags {
+                index.remove(&(StringKey(tag.clone()), event_id));
             }
+            for tag in tags {
+                index.insert((StringKey(tag.clone()), event_id), ());
+            }
+        });
+    }
+}
 
-            // Fetch ticket price from TICKETS_STORAGE
-            let ticket_price = TICKETS_STORAGE
-                .with(|service| {
-                    let borrowed_service = service.borrow();
-                    // Find the ticket by event_id and ticket_type
-                    borrowed_service
-                        .iter()
-                        .find(|(_, ticket)| {
-                            ticket.event_id == event_id
-                                && &ticket.ticket_type == &ticket_type.to_string()
-                        })
-                        .map(|(_, ticket)| ticket.ticket_price)
-                })
-                .unwrap_or_else(|| {
-                    // If the ticket price is not found, return a default price or handle the error as needed
-                    ic_cdk::print(
-                        "Ticket price not found for the specified ticket type and event ID.",
-                    );
-                    0
-                });
-
-            // Calculate total cost
-            let total_cost = ticket_price * num_tickets as u64;
-
-            // Create tickets for the purchase
-            let mut tickets = Vec::new();
-            for _ in 0..num_tickets {
-                // Fetch a ticket from TICKETS_STORAGE
-                let ticket = TICKETS_STORAGE.with(|service| {
-                    let mut borrowed_service = service.borrow_mut();
-                    // Find and remove the first available ticket by event_id and ticket_type
-                    let ticket = borrowed_service
-                        .iter()
-                        .find(|(_, ticket)| {
-                            ticket.event_id == event_id
-                                && &ticket.ticket_type == &ticket_type.to_string()
-                        })
-                        .map(|(id, mut ticket)| {
-                            let cloned_ticket = ticket.clone();
-                            if ticket.num_tickets == 1 {
-                                // If it's the last ticket of this type, remove it from storage
-                                borrowed_service.remove(&id);
-                            } else {
-                                // Decrement the number of available tickets
-                                ticket.num_tickets -= 1;
-                            }
-                            cloned_ticket
-                        });
-                    ticket
-                });
+// Full-text search over the name, details and location of the listed events. Every word of
+// the query has to match a word of the event or the start of one.
+#[ic_cdk::query]
+fn search_events(query: String, filters: SearchFilters, page: u32) -> Result<SearchResults, Error> {
+    let mut terms: Vec<String> = tokenize(&query).collect();
+    terms.sort();
+    terms.dedup();
+    if terms.is_empty() {
+        return Err(Error::CustomError(format!(
+            "Search query needs a word of at least {} characters",
+            MIN_SEARCH_TERM_LEN
+        )));
+    }
+    if terms.len() > MAX_SEARCH_QUERY_TERMS {
+        return Err(Error::CustomError(format!(
+            "Search query cannot have more than {} words",
+            MAX_SEARCH_QUERY_TERMS
+        )));
+    }
 
-                if let Some(ticket) = ticket {
-                    tickets.push(ticket);
+    // Exact word matches count double, prefix matches count once
+    let mut scores: BTreeMap<u64, (usize, u32)> = BTreeMap::new();
+    SEARCH_INDEX.with(|index| {
+        let index = index.borrow();
+        for term in &terms {
+            let prefix = term.chars().count() >= MIN_SEARCH_PREFIX_LEN;
+            let mut term_scores: BTreeMap<u64, u32> = BTreeMap::new();
+            for ((indexed, event_id), weight) in index
+                .range((StringKey(term.clone()), 0)..)
+                .take_while(|((indexed, _), _)| {
+                    if prefix {
+                        indexed.0.starts_with(term.as_str())
+                    } else {
+                        indexed.0 == *term
+                    }
+                })
+                .take(MAX_SEARCH_TERM_MATCHES)
+            {
+                let score = if indexed.0 == *term {
+                    weight * 2
                 } else {
-                    // Handle the case where a ticket is not found (should not occur if availability is properly checked)
-                    return Err(Error::CustomError(format!(
-                        "Failed to find available ticket for type: {}",
-                        ticket_type
-                    )));
-                }
+                    weight
+                };
+                *term_scores.entry(event_id).or_default() += score;
             }
+            for (event_id, score) in term_scores {
+                let entry = scores.entry(event_id).or_default();
+                entry.0 += 1;
+                entry.1 += score;
+            }
+        }
+    });
 
-            // Update the event with the attendee's name
-            add_attendees(
-                UserPayload1 {
-                    username: payload1.username,
-                    password: payload1.password,
-                },
-                event_id,
-                AttendeePayload {
-                    attendee_name: attendee_name,
-                },
-            )
-            .expect("Failed to add attendee");
+    let location = filters
+        .location
+        .as_ref()
+        .map(|location| location.to_lowercase());
+    // Ranked from the index alone, so only the events up to the requested page are loaded
+    let mut ranked: Vec<(u64, u32)> = scores
+        .into_iter()
+        .filter(|(_, (matched, _))| *matched == terms.len())
+        .map(|(event_id, (_, score))| (event_id, score))
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
 
-            // Return purchased tickets and total cost
-            Ok((tickets, total_cost))
+    let mut matches = ranked
+        .into_iter()
+        .filter_map(|(event_id, score)| {
+            _get_event(&event_id).map(|event| SearchResult { event, score })
+        })
+        .filter(|result| {
+            let event = &result.event;
+            event.status != EventStatus::Draft
+                && filters.status.is_none_or(|status| event.status == status)
+                && filters.from.is_none_or(|from| event.end_date > from)
+                && filters.to.is_none_or(|to| event.start_date < to)
+                && location.as_ref().is_none_or(|location| {
+                    event.location.to_lowercase().contains(location.as_str())
+                })
+        })
+        .skip(page as usize * SEARCH_PAGE_SIZE);
+    let results = matches.by_ref().take(SEARCH_PAGE_SIZE).collect();
+    Ok(SearchResults {
+        results,
+        has_more: matches.next().is_some(),
+        page,
+        page_size: SEARCH_PAGE_SIZE as u32,
+    })
+}
+
+// Splits text into lowercase search terms.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_SEARCH_TERM_LEN)
+        .map(|word| {
+            word.to_lowercase()
+                .chars()
+                .take(MAX_SEARCH_TERM_LEN)
+                .collect()
+        })
+}
+
+// The weighted search terms of an event.
+fn search_terms(event: &Event) -> BTreeMap<String, u32> {
+    let mut terms = BTreeMap::new();
+    for (text, weight) in [
+        (&event.event_name, SEARCH_WEIGHT_NAME),
+        (&event.location, SEARCH_WEIGHT_LOCATION),
+        (&event.details, SEARCH_WEIGHT_DETAILS),
+    ] {
+        for term in tokenize(text) {
+            *terms.entry(term).or_default() += weight;
         }
-        None => Err(Error::NotFound {
-            msg: format!("Event with ID {} not found.", event_id),
-        }),
     }
+    terms
 }
 
-// helper method to perform insert for events.
-fn do_insert_event(event: &Event) {
-    EVENTS_STORAGE.with(|service| service.borrow_mut().insert(event.id, event.clone()));
+// Replaces the index entries of an event, only touching the index when its text changed.
+fn update_search_index(event_id: u64, previous: Option<&Event>, event: Option<&Event>) {
+    let previous_terms = previous.map(search_terms).unwrap_or_default();
+    let terms = event.map(search_terms).unwrap_or_default();
+    if previous_terms == terms {
+        return;
+    }
+
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for term in previous_terms.into_keys() {
+            index.remove(&(StringKey(term), event_id));
+        }
+        for (term, weight) in terms {
+            index.insert((StringKey(term), event_id), weight);
+        }
+    });
 }
 
-// helper method to perform insert for tickets.
-fn do_insert_ticket(ticket: &Ticket) {
+// Indexes the events stored before search existed.
+fn backfill_search_index() {
+    if !SEARCH_INDEX.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    let events: Vec<Event> =
+        EVENTS_STORAGE.with(|service| service.borrow().iter().map(|(_, event)| event).collect());
+    for event in events {
+        update_search_index(event.id, None, Some(&event));
+    }
+}
+
+// a helper method to get all tickets of an event.
+fn get_event_tickets(event_id: u64) -> Vec<Ticket> {
+    let ticket_ids: Vec<u64> = EVENT_TICKETS.with(|index| {
+        index
+            .borrow()
+            .range((event_id, 0)..=(event_id, u64::MAX))
+            .map(|((_, ticket_id), _)| ticket_id)
+            .collect()
+    });
     TICKETS_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .insert(ticket.ticket_id, ticket.clone())
+        let service = service.borrow();
+        ticket_ids
+            .into_iter()
+            .filter_map(|ticket_id| service.get(&ticket_id))
+            .collect()
+    })
+}
+
+// Indexes the tickets stored before the event index existed.
+fn backfill_ticket_index() {
+    if !EVENT_TICKETS.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    let tickets: Vec<Ticket> =
+        TICKETS_STORAGE.with(|service| service.borrow().iter().map(|(_, ticket)| ticket).collect());
+    EVENT_TICKETS.with(|index| {
+        let mut index = index.borrow_mut();
+        for ticket in tickets {
+            index.insert((ticket.event_id, ticket.ticket_id), ());
+        }
     });
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
-enum Error {
-    NotFound { msg: String },
-    AlreadyExists { msg: String },
-    CustomError(String),
+// Indexes the events stored before the start date index existed.
+fn backfill_date_index() {
+    if !EVENT_DATE_INDEX.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    let events: Vec<Event> =
+        EVENTS_STORAGE.with(|service| service.borrow().iter().map(|(_, event)| event).collect());
+    for event in events {
+        update_date_index(event.id, None, Some(&event));
+    }
 }
 
 // a helper method to get an event by id.
@@ -957,3 +6232,301 @@ fn _get_event(id: &u64) -> Option<Event> {
 
 // need this to generate candid
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4226 appendix D
+    #[test]
+    fn hotp_matches_rfc4226_vectors() {
+        let secret = b"12345678901234567890";
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, value) in expected.iter().enumerate() {
+            assert_eq!(hotp(secret, counter as u64), *value, "counter {}", counter);
+        }
+    }
+
+    // RFC 6238 appendix B for SHA-1, whose 8 digit values end in our 6 digit codes
+    #[test]
+    fn hotp_matches_rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+        for (unix_time, value) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(
+                hotp(secret, unix_time / TOTP_PERIOD_SECS),
+                value,
+                "time {}",
+                unix_time
+            );
+        }
+    }
+
+    // RFC 4648 section 10, without padding
+    #[test]
+    fn base32_matches_rfc4648_vectors() {
+        for (input, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(input.as_bytes()), encoded);
+        }
+    }
+
+    fn nanos(rfc3339: &str) -> u64 {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .timestamp_nanos_opt()
+            .unwrap() as u64
+    }
+
+    fn rule(frequency: Frequency, count: u32) -> RecurrenceRule {
+        RecurrenceRule {
+            frequency,
+            interval: 1,
+            count: Some(count),
+            until: None,
+            exceptions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn expand_recurrence_moves_occurrences_out_of_a_dst_gap() {
+        // 02:30 doesn't exist in Berlin on 2024-03-31, clocks jump from 02:00 to 03:00
+        let start = nanos("2024-03-30T02:30:00+01:00");
+        let dates = expand_recurrence(
+            &rule(Frequency::Daily, 3),
+            start,
+            start + 3_600_000_000_000,
+            "Europe/Berlin",
+        )
+        .unwrap();
+        let starts: Vec<u64> = dates.iter().map(|(start, _)| *start).collect();
+        assert_eq!(
+            starts,
+            [
+                nanos("2024-03-30T02:30:00+01:00"),
+                nanos("2024-03-31T03:30:00+02:00"),
+                nanos("2024-04-01T02:30:00+02:00"),
+            ]
+        );
+        assert!(dates
+            .iter()
+            .all(|(start, end)| end - start == 3_600_000_000_000));
+    }
+
+    #[test]
+    fn expand_recurrence_takes_the_earlier_of_repeated_local_times() {
+        // 02:30 happens twice in Berlin on 2024-10-27, clocks go back from 03:00 to 02:00
+        let start = nanos("2024-10-26T02:30:00+02:00");
+        let dates =
+            expand_recurrence(&rule(Frequency::Daily, 3), start, start, "Europe/Berlin").unwrap();
+        let starts: Vec<u64> = dates.iter().map(|(start, _)| *start).collect();
+        assert_eq!(
+            starts,
+            [
+                nanos("2024-10-26T02:30:00+02:00"),
+                nanos("2024-10-27T02:30:00+02:00"),
+                nanos("2024-10-28T02:30:00+01:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_recurrence_clamps_to_the_end_of_shorter_months() {
+        let start = nanos("2024-01-31T10:00:00Z");
+        let dates = expand_recurrence(&rule(Frequency::Monthly, 4), start, start, "UTC").unwrap();
+        let starts: Vec<u64> = dates.iter().map(|(start, _)| *start).collect();
+        // Every month counts from the first occurrence, so March is back on the 31st
+        assert_eq!(
+            starts,
+            [
+                nanos("2024-01-31T10:00:00Z"),
+                nanos("2024-02-29T10:00:00Z"),
+                nanos("2024-03-31T10:00:00Z"),
+                nanos("2024-04-30T10:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_recurrence_skips_exceptions_after_counting() {
+        let start = nanos("2024-05-01T09:00:00Z");
+        let mut weekly = rule(Frequency::Weekly, 3);
+        weekly.exceptions = vec!["2024-05-08".to_string()];
+        let dates = expand_recurrence(&weekly, start, start, "UTC").unwrap();
+        let starts: Vec<u64> = dates.iter().map(|(start, _)| *start).collect();
+        assert_eq!(
+            starts,
+            [nanos("2024-05-01T09:00:00Z"), nanos("2024-05-15T09:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn tokenize_splits_unicode_words() {
+        let terms: Vec<String> = tokenize("Café Zürich — 東京 2024! Rock'n'Roll, ÉTÉ").collect();
+        assert_eq!(
+            terms,
+            ["café", "zürich", "東京", "2024", "rock", "roll", "été"]
+        );
+    }
+
+    #[test]
+    fn tokenize_truncates_long_words_by_characters() {
+        let word = "ж".repeat(MAX_SEARCH_TERM_LEN + 8);
+        let terms: Vec<String> = tokenize(&word).collect();
+        assert_eq!(terms, ["ж".repeat(MAX_SEARCH_TERM_LEN)]);
+    }
+
+    #[test]
+    fn longest_category_fits_in_storage() {
+        let category = Category {
+            id: u64::MAX,
+            name: "𝄞".repeat(MAX_CATEGORY_NAME_LEN),
+            description: "𝄞".repeat(MAX_CATEGORY_DESCRIPTION_LEN),
+            created_at: u64::MAX,
+        };
+        assert!(category.to_bytes().len() <= Category::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn longest_venue_fits_in_storage() {
+        let field = "𝄞".repeat(MAX_VENUE_FIELD_LEN);
+        let venue = Venue {
+            id: u64::MAX,
+            name: field.clone(),
+            address: Address {
+                street: field.clone(),
+                city: field.clone(),
+                region: field.clone(),
+                postal_code: field.clone(),
+                country: field,
+            },
+            latitude: -90.0,
+            longitude: -180.0,
+            capacity: u64::MAX,
+            accessibility: Accessibility {
+                wheelchair_accessible: true,
+                accessible_restrooms: true,
+                hearing_loop: true,
+                notes: "𝄞".repeat(MAX_ACCESSIBILITY_NOTES_LEN),
+            },
+            created_by: u64::MAX,
+            created_at: u64::MAX,
+        };
+        assert!(venue.to_bytes().len() <= Venue::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn geohash_matches_published_values() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(42.6, -5.6, 5), "ezs42");
+        assert_eq!(geohash(-90.0, -180.0, 4), "0000");
+        assert_eq!(geohash(90.0, 180.0, 4), "zzzz");
+    }
+
+    #[test]
+    fn geohash_search_cells_wrap_around_the_antimeridian() {
+        let cells = geohash_search_cells(0.0, 179.99, 10.0);
+        let covered = |latitude: f64, longitude: f64| {
+            let hash = geohash(latitude, longitude, GEOHASH_PRECISION);
+            cells.iter().any(|cell| hash.starts_with(cell.as_str()))
+        };
+        assert!(covered(0.0, 179.99));
+        // About 2 km away, across the antimeridian
+        assert!(covered(0.0, -179.99));
+        assert!(covered(0.05, -179.95));
+    }
+
+    #[test]
+    fn geohash_search_cells_cover_everything_near_a_pole() {
+        assert_eq!(geohash_search_cells(89.9, 0.0, 50.0), [String::new()]);
+    }
+
+    #[test]
+    fn list_events_by_date_pages_in_both_directions() {
+        for (id, start_date) in [(1, 30), (2, 10), (3, 20), (4, 10), (5, 40)] {
+            do_insert_event(&Event {
+                id,
+                start_date,
+                end_date: start_date + 1,
+                status: EventStatus::Published,
+                ..Default::default()
+            });
+        }
+        let page_through = |sort: EventSort| {
+            let mut ids = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page =
+                    list_events_by_date(sort, cursor.as_ref(), 2, None, |event| event.id != 3);
+                ids.extend(page.events.iter().map(|event| event.id));
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => return ids,
+                }
+            }
+        };
+        assert_eq!(page_through(EventSort::StartDate), [2, 4, 1, 5]);
+        assert_eq!(page_through(EventSort::StartDateDesc), [5, 1, 4, 2]);
+
+        // A page continues after its cursor even once that event is gone
+        let cursor = EventCursor {
+            key: EventSortKey::Time(10),
+            id: 4,
+        };
+        EVENTS_STORAGE.with(|service| service.borrow_mut().remove(&4));
+        update_date_index(
+            4,
+            Some(&Event {
+                id: 4,
+                start_date: 10,
+                ..Default::default()
+            }),
+            None,
+        );
+        let page = list_events_by_date(EventSort::StartDate, Some(&cursor), 10, None, |_| true);
+        let ids: Vec<u64> = page.events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, [3, 1, 5]);
+        let page = list_events_by_date(EventSort::StartDateDesc, Some(&cursor), 10, None, |_| true);
+        let ids: Vec<u64> = page.events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, [2]);
+    }
+
+    #[test]
+    fn longest_event_fits_in_storage() {
+        let event = Event {
+            id: u64::MAX,
+            event_name: "a".repeat(MAX_EVENT_NAME_LEN),
+            details: "a".repeat(MAX_EVENT_DETAILS_LEN),
+            location: "a".repeat(MAX_EVENT_LOCATION_LEN),
+            start_date: u64::MAX,
+            end_date: u64::MAX,
+            // The longest IANA timezone name
+            timezone: "America/Argentina/ComodRivadavia".to_string(),
+            timestamp: u64::MAX,
+            organizer_id: u64::MAX,
+            co_organizer_ids: vec![u64::MAX; MAX_CO_ORGANIZERS],
+            status: EventStatus::Postponed,
+            series_id: Some(u64::MAX),
+            category_id: Some(u64::MAX),
+            // A 4-byte letter, tags may only contain letters, digits and dashes
+            tags: Some(vec!["𠀀".repeat(MAX_TAG_LEN); MAX_EVENT_TAGS]),
+            venue_id: Some(u64::MAX),
+        };
+        assert!(event.to_bytes().len() <= Event::MAX_SIZE as usize);
+    }
+}